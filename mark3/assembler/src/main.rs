@@ -1,24 +1,48 @@
 // extern crate strum;
 
-use std::io::{self, BufRead};
+use std::fs;
+use std::io::{self, ErrorKind, Read};
 
 use common::*;
 
 fn main() -> Result<(), std::io::Error> {
 
-    let lines = {
-        let mut lines = Vec::new();
-        let stdin = io::stdin();
-        for line in stdin.lock().lines() {
-            let line = line?;
-            lines.push(Line::parse(line));
+    let trace = std::env::args().any(|a| a == "--trace");
+
+    let path = std::env::args().skip(1).find(|a| !a.starts_with("--"));
+    // GETC reads the --input file, or stdin when it doesn't hold the source
+    let args : Vec<String> = std::env::args().collect();
+    let console = Console::from_args(&args, path.is_none())?;
+
+    let source = match &path {
+        Some(path) => fs::read_to_string(path)?,
+        None => {
+            let mut s = String::new();
+            let stdin = io::stdin();
+            stdin.lock().read_to_string(&mut s)?;
+            s
         }
-        lines
     };
 
-    let rom = assemble(lines);
+    let rom = match assemble_source(path.as_deref().unwrap_or("<stdin>"), &source, true) {
+        Ok((rom, _)) => rom,
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("{}", e);
+            }
+            return Err(std::io::Error::from(ErrorKind::InvalidData));
+        }
+    };
 
-    simulate(&rom, 10000);
+    let mut peripherals : Vec<Box<dyn Peripheral>> = vec![Box::new(console)];
+    let simulated = configure(std::env::args()).and_then(|configured| {
+        peripherals.extend(configured);
+        simulate(&encode_rom(&rom), 10000, trace, peripherals)
+    });
+    if let Err(e) = simulated {
+        eprintln!("error: {}", e);
+        return Err(std::io::Error::from(ErrorKind::InvalidInput));
+    }
 
     Ok(())
 }
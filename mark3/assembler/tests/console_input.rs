@@ -0,0 +1,42 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// echoes CONSOLE_IN (f1) to CONSOLE_OUT (f0) until the input runs out
+const ECHO : &str = "
+:loop
+  loadlo 1
+  loadhi f
+  storeaddr
+  loadmem push
+  jz :done
+  loadlo 0
+  loadhi f
+  storeaddr
+  popdiscard 0
+  storemem
+  jmp :loop
+:done
+  halt
+";
+
+#[test]
+fn getc_reads_the_input_file_when_the_source_is_on_stdin() {
+    let input = std::env::temp_dir().join(format!("assembler-input-{}", std::process::id()));
+    std::fs::write(&input, "mark3").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_assembler"))
+        .arg(format!("--input={}", input.display()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(ECHO.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // the listing comes first; the program's output follows the start of the simulation
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("# begin simulation\nmark3# simulation completed"), "{}", stdout);
+}
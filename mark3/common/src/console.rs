@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+use crate::Peripheral;
+
+// memory from IO_BASE to the top is the I/O page; programs keep their stack below it
+pub const IO_BASE: u8 = 0xF0;
+
+// storing to CONSOLE_OUT writes a character; loading from CONSOLE_IN reads the next one,
+// or 0 once the input is exhausted
+pub const CONSOLE_OUT: u8 = 0xF0;
+pub const CONSOLE_IN: u8 = 0xF1;
+
+// the stack instructions always address RAM, so only LoadMem and StoreMem reach the console
+pub struct Console {
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+}
+
+impl Console {
+    pub fn new(input: Box<dyn Read>, output: Box<dyn Write>) -> Console {
+        Console { input, output }
+    }
+
+    pub fn stdio() -> Console {
+        Console::new(Box::new(io::stdin()), Box::new(io::stdout()))
+    }
+
+    // the console for a command line tool: output to stdout, input from the --input file if
+    // one is given, else from stdin unless the tool already read its source (or commands)
+    // from there, in which case there is no input
+    pub fn from_args(args: &[String], stdin_taken: bool) -> io::Result<Console> {
+        let input : Box<dyn Read> = match args.iter().find_map(|a| a.strip_prefix("--input=")) {
+            Some(path) => Box::new(File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?),
+            None if stdin_taken => Box::new(io::empty()),
+            None => Box::new(io::stdin()),
+        };
+        Ok(Console::new(input, Box::new(io::stdout())))
+    }
+}
+
+impl Peripheral for Console {
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn range(&self) -> RangeInclusive<u8> {
+        CONSOLE_OUT..=CONSOLE_IN
+    }
+
+    fn read(&mut self, addr: u8) -> u8 {
+        let mut byte = [0];
+        if addr != CONSOLE_IN || self.input.read_exact(&mut byte).is_err() {
+            return 0;
+        }
+        byte[0]
+    }
+
+    fn write(&mut self, addr: u8, value: u8) {
+        if addr == CONSOLE_OUT {
+            // like a terminal that has gone away, a closed output just drops characters;
+            // flushing keeps the output in step with the trace
+            let _ = self.output.write_all(&[value]);
+            let _ = self.output.flush();
+        }
+    }
+}
@@ -2,12 +2,17 @@ extern crate strum;
 #[macro_use]
 extern crate strum_macros;
 
-use std::str::FromStr;
+use std::fmt;
 
 use std::num::Wrapping;
 
 use std::collections::BTreeMap;
 
+mod console;
+pub use console::*;
+mod peripherals;
+pub use peripherals::*;
+
 #[derive(Clone, Copy, Debug, Display, EnumString, Eq, PartialEq, PartialOrd, Ord)]
 #[strum(serialize_all = "lowercase")]
 pub enum Reg {
@@ -27,11 +32,46 @@ pub enum Target {
 }
 
 impl Target {
-    pub fn parse(s: &str) -> Target {
+    pub fn parse(s: &str) -> Result<Target, String> {
         match s.chars().next() {
-            Some(':') => Target::Label(s.to_owned()),
-            Some(_) => Target::Absolute(u8::from_str_radix(s, 16).expect("invalid hex")),
-            None => panic!("argument needed.")
+            Some(':') if s.len() > 1 => Ok(Target::Label(s.to_owned())),
+            Some(':') => Err("empty label".to_owned()),
+            Some(_) => u8::from_str_radix(s, 16)
+                .map(Target::Absolute)
+                .map_err(|_| format!("invalid hex '{}'", s)),
+            None => Err("argument needed".to_owned()),
+        }
+    }
+
+    // the address this target refers to
+    fn resolve(&self, pc: u8, labels: &BTreeMap<String,u8>) -> Result<u8, String> {
+        match self {
+            Target::Absolute(c) => Ok(*c),
+            Target::Offset(o) => pc.checked_add(*o).ok_or_else(|| format!("pc+{:x} is past the end of the ROM", o)),
+            Target::Label(l) => labels.get(l).cloned().ok_or_else(|| format!("undefined label {}", l)),
+        }
+    }
+
+    fn absolute(&self) -> u8 {
+        match self {
+            Target::Absolute(c) => *c,
+            _ => unreachable!(),
+        }
+    }
+
+    fn nibble(&self) -> u8 {
+        let c = self.absolute();
+        assert!(c <= 0xf, "immediate {:x} does not fit in 4 bits", c);
+        c
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Label(l) => write!(f, "{}", l),
+            Target::Absolute(c) => write!(f, "{:x}", c),
+            Target::Offset(o) => write!(f, "+{:x}", o),
         }
     }
 }
@@ -46,12 +86,21 @@ impl StackOffset {
 
     pub fn new(val: u8) -> StackOffset {
         assert!(val < 8);
-        StackOffset(val as u8)
+        StackOffset(val)
     }
 
-    pub fn parse(s: &str) -> StackOffset {
-        let val = u8::from_str_radix(s, 16).expect("invalid hex");
-        StackOffset::new(val)
+    pub fn parse(s: &str) -> Result<StackOffset, String> {
+        match u8::from_str_radix(s, 16) {
+            Ok(val) if val < 8 => Ok(StackOffset::new(val)),
+            Ok(val) => Err(format!("stack offset {:x} is out of range (0-7)", val)),
+            Err(_) => Err(format!("invalid hex '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for StackOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x}", self.0)
     }
 }
 
@@ -71,19 +120,65 @@ pub enum PushableInstruction {
 }
 
 impl PushableInstruction {
-    fn resolve_pushable(&self, pc: u8, labels: &BTreeMap<&String,u8>) -> PushableInstruction {
+    // low 7 bits; the high bit selects whether ACC is pushed afterwards
+    fn encode(&self) -> u8 {
         match self {
-            PushableInstruction::LoadLo(t) => match t {
-                Target::Absolute(_) => self.clone(),
-                Target::Offset(o) => PushableInstruction::LoadLo(Target::Absolute(pc + o)),
-                Target::Label(l) => PushableInstruction::LoadLo(Target::Absolute(labels[l] & 0xf)),
-            },
-            PushableInstruction::LoadHi(t) => match t {
-                Target::Absolute(_) => self.clone(),
-                Target::Offset(o) => PushableInstruction::LoadHi(Target::Absolute(((pc + o) >> 4) & 0xf)),
-                Target::Label(l) => PushableInstruction::LoadHi(Target::Absolute((labels[l] >> 4) & 0xf)),
-            },
+            PushableInstruction::LoadLo(t) => t.nibble(),
+            PushableInstruction::LoadHi(t) => 0x10 | t.nibble(),
+            PushableInstruction::Add(o) => 0x40 | o.0,
+            PushableInstruction::Xor(o) => 0x48 | o.0,
+            PushableInstruction::Not(o) => 0x50 | o.0,
+            PushableInstruction::Or(o) => 0x58 | o.0,
+            PushableInstruction::And(o) => 0x60 | o.0,
+            PushableInstruction::Mul(o) => 0x68 | o.0,
+            PushableInstruction::LoadFromStack(o) => 0x70 | o.0,
+            PushableInstruction::LoadMem => 0x78,
+            PushableInstruction::LoadPc => 0x79,
+        }
+    }
+
+    fn decode(bits: u8) -> Option<PushableInstruction> {
+        let offset = StackOffset(bits & 0x7);
+        Some(match bits {
+            0x00..=0x0f => PushableInstruction::LoadLo(Target::Absolute(bits & 0xf)),
+            0x10..=0x1f => PushableInstruction::LoadHi(Target::Absolute(bits & 0xf)),
+            0x40..=0x47 => PushableInstruction::Add(offset),
+            0x48..=0x4f => PushableInstruction::Xor(offset),
+            0x50..=0x57 => PushableInstruction::Not(offset),
+            0x58..=0x5f => PushableInstruction::Or(offset),
+            0x60..=0x67 => PushableInstruction::And(offset),
+            0x68..=0x6f => PushableInstruction::Mul(offset),
+            0x70..=0x77 => PushableInstruction::LoadFromStack(offset),
+            0x78 => PushableInstruction::LoadMem,
+            0x79 => PushableInstruction::LoadPc,
+            _ => return None,
+        })
+    }
+
+    fn resolve_pushable(&self, pc: u8, labels: &BTreeMap<String,u8>) -> Result<PushableInstruction, String> {
+        Ok(match self {
+            PushableInstruction::LoadLo(Target::Absolute(_)) | PushableInstruction::LoadHi(Target::Absolute(_)) => self.clone(),
+            PushableInstruction::LoadLo(t) => PushableInstruction::LoadLo(Target::Absolute(t.resolve(pc, labels)? & 0xf)),
+            PushableInstruction::LoadHi(t) => PushableInstruction::LoadHi(Target::Absolute((t.resolve(pc, labels)? >> 4) & 0xf)),
             _ => self.clone()
+        })
+    }
+}
+
+impl fmt::Display for PushableInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PushableInstruction::LoadLo(t) => write!(f, "loadlo {}", t),
+            PushableInstruction::LoadHi(t) => write!(f, "loadhi {}", t),
+            PushableInstruction::Add(o) => write!(f, "add {}", o),
+            PushableInstruction::Xor(o) => write!(f, "xor {}", o),
+            PushableInstruction::Not(o) => write!(f, "not {}", o),
+            PushableInstruction::Or(o) => write!(f, "or {}", o),
+            PushableInstruction::And(o) => write!(f, "and {}", o),
+            PushableInstruction::Mul(o) => write!(f, "mul {}", o),
+            PushableInstruction::LoadFromStack(o) => write!(f, "loadfromstack {}", o),
+            PushableInstruction::LoadMem => write!(f, "loadmem"),
+            PushableInstruction::LoadPc => write!(f, "loadpc"),
         }
     }
 }
@@ -103,6 +198,8 @@ pub enum Instruction {
     PopDiscard(StackOffset),
     WithPush(PushableInstruction),
     WithoutPush(PushableInstruction),
+    // a byte that is not a valid opcode, written "?? xx"; executing it stops the machine
+    Unknown(u8),
 }
 
 impl Instruction {
@@ -124,61 +221,152 @@ impl Instruction {
         }
     }
 
+    // see stacker.txt for the bit layout
     pub fn encode(&self) -> (u8,Option<u8>) {
         match self {
-            Instruction::Jmp(t) => (0x3c, match t { 
-                Target::Absolute(c) => Some(*c),
-                _ => unreachable!(),
-            }),
-            Instruction::Jnz(t) => (0x3d, match t { 
-                Target::Absolute(c) => Some(*c),
-                _ => unreachable!(),
-            }),
-            Instruction::Jz(t) => (0x3e, match t { 
-                Target::Absolute(c) => Some(*c),
-                _ => unreachable!(),
-            }),
-            _ => (0, None)
-        }
-    }
-
-    pub fn parse(line: &str) -> Instruction {
-        let tokens : Vec<&str> = line.split_whitespace().collect();
-
-        let pushable = match tokens[0].to_lowercase().as_ref() {
-            "loadlo" => Some(PushableInstruction::LoadLo(Target::parse(tokens[1]))),
-            "loadhi" => Some(PushableInstruction::LoadHi(Target::parse(tokens[1]))),
-            "add" => Some(PushableInstruction::Add(StackOffset::parse(tokens[1]))),
-            "xor" => Some(PushableInstruction::Xor(StackOffset::parse(tokens[1]))),
-            "not" => Some(PushableInstruction::Not(StackOffset::parse(tokens[1]))),
-            "or" => Some(PushableInstruction::Or(StackOffset::parse(tokens[1]))),
-            "and" => Some(PushableInstruction::And(StackOffset::parse(tokens[1]))),
-            "mul" => Some(PushableInstruction::Mul(StackOffset::parse(tokens[1]))),
-            "loadfromstack" => Some(PushableInstruction::LoadFromStack(StackOffset::parse(tokens[1]))),
-            "loadmem" => Some(PushableInstruction::LoadMem),
-            "loadpc" => Some(PushableInstruction::LoadPc),
+            Instruction::WithPush(p) => (0x80 | p.encode(), None),
+            Instruction::WithoutPush(p) => (p.encode(), None),
+            Instruction::StoreAddr => (0x20, None),
+            Instruction::StoreMem => (0x21, None),
+            Instruction::JmpAcc => (0x22, None),
+            Instruction::Alloc(o) => (0x28 | o.0, None),
+            Instruction::Jmp(t) => (0x3c, Some(t.absolute())),
+            Instruction::Jnz(t) => (0x3d, Some(t.absolute())),
+            Instruction::Jz(t) => (0x3e, Some(t.absolute())),
+            Instruction::StoreToStack(o) => (0xa0 | o.0, None),
+            Instruction::Discard(o) => (0xa8 | o.0, None),
+            Instruction::PopDiscard(o) => (0xb8 | o.0, None),
+            Instruction::Unknown(b) => (*b, None),
+        }
+    }
+
+    // returns the instruction at the start of bytes and its size, or None if bytes is empty
+    pub fn decode(bytes: &[u8]) -> Option<(Instruction, usize)> {
+        let opcode = *bytes.first()?;
+        let offset = StackOffset(opcode & 0x7);
+        let target = match bytes.get(1) {
+            Some(t) => Target::Absolute(*t),
+            None => Target::Offset(0),
+        };
+
+        let instruction = match opcode {
+            0x20 => Instruction::StoreAddr,
+            0x21 => Instruction::StoreMem,
+            0x22 => Instruction::JmpAcc,
+            0x28..=0x2f => Instruction::Alloc(offset),
+            0x3c => Instruction::Jmp(target),
+            0x3d => Instruction::Jnz(target),
+            0x3e => Instruction::Jz(target),
+            0xa0..=0xa7 => Instruction::StoreToStack(offset),
+            0xa8..=0xaf => Instruction::Discard(offset),
+            0xb8..=0xbf => Instruction::PopDiscard(offset),
+            0x20..=0x3f | 0xa0..=0xbf => Instruction::Unknown(opcode),
+            _ => match PushableInstruction::decode(opcode & 0x7f) {
+                Some(p) => Instruction::with_push(opcode & 0x80 != 0, p),
+                None => Instruction::Unknown(opcode),
+            }
+        };
+
+        let size = instruction.get_size() as usize;
+        if size > bytes.len() {
+            // truncated jump
+            return Some((Instruction::Unknown(opcode), 1));
+        }
+        Some((instruction, size))
+    }
+
+    pub fn parse(line: &str) -> Result<Instruction, ParseError> {
+        let tokens = tokenize(line);
+        let (column, mnemonic) = match tokens.first() {
+            Some(token) => *token,
+            None => return Err(ParseError::new(1, "expected an instruction")),
+        };
+        let opcode = mnemonic.to_lowercase();
+
+        let operand = |n: usize| match tokens.get(n) {
+            Some(token) => Ok(*token),
+            None => {
+                let (column, token) = tokens[n - 1];
+                Err(ParseError::new(column + token.len(), format!("{} needs an operand", opcode)))
+            }
+        };
+        let target = |n: usize| {
+            let (column, token) = operand(n)?;
+            Target::parse(token).map_err(|e| ParseError::new(column, e))
+        };
+        let immediate = |n: usize| match target(n)? {
+            Target::Absolute(c) if c > 0xf => Err(ParseError::new(tokens[n].0,
+                format!("immediate {:x} does not fit in 4 bits", c))),
+            t => Ok(t),
+        };
+        let offset = |n: usize| {
+            let (column, token) = operand(n)?;
+            StackOffset::parse(token).map_err(|e| ParseError::new(column, e))
+        };
+
+        let pushable = match opcode.as_ref() {
+            "loadlo" => Some((PushableInstruction::LoadLo(immediate(1)?), 1)),
+            "loadhi" => Some((PushableInstruction::LoadHi(immediate(1)?), 1)),
+            "add" => Some((PushableInstruction::Add(offset(1)?), 1)),
+            "xor" => Some((PushableInstruction::Xor(offset(1)?), 1)),
+            "not" => Some((PushableInstruction::Not(offset(1)?), 1)),
+            "or" => Some((PushableInstruction::Or(offset(1)?), 1)),
+            "and" => Some((PushableInstruction::And(offset(1)?), 1)),
+            "mul" => Some((PushableInstruction::Mul(offset(1)?), 1)),
+            "loadfromstack" => Some((PushableInstruction::LoadFromStack(offset(1)?), 1)),
+            "loadmem" => Some((PushableInstruction::LoadMem, 0)),
+            "loadpc" => Some((PushableInstruction::LoadPc, 0)),
             _ => None,
         };
 
-        if let Some(pushable) = pushable {
-            return match tokens.last().unwrap() {
-                &"push" => Instruction::WithPush(pushable),
-                _ => Instruction::WithoutPush(pushable),
+        if let Some((pushable, operands)) = pushable {
+            let push = match tokens.get(operands + 1) {
+                Some((_, token)) => token.eq_ignore_ascii_case("push"),
+                None => false,
             };
+            expect_end(&tokens, operands + 1 + push as usize)?;
+            return Ok(Instruction::with_push(push, pushable));
         }
 
-        match tokens[0].to_lowercase().as_ref() {
-            "storeaddr" => Instruction::StoreAddr,
-            "storemem" => Instruction::StoreMem,
-            "jmpacc" => Instruction::JmpAcc,
-            "jmp" => Instruction::Jmp(Target::parse(tokens[1])),
-            "jz" => Instruction::Jz(Target::parse(tokens[1])),
-            "jnz" => Instruction::Jnz(Target::parse(tokens[1])),
-            "storetostack" => Instruction::StoreToStack(StackOffset::parse(tokens[1])),
-            "discard" => Instruction::Discard(StackOffset::parse(tokens[1])),
-            "popdiscard" => Instruction::PopDiscard(StackOffset::parse(tokens[1])),
-            "alloc" => Instruction::Alloc(StackOffset::parse(tokens[1])),
-            _ => panic!("unknown opcode {}", tokens[0])
+        let (instruction, operands) = match opcode.as_ref() {
+            "storeaddr" => (Instruction::StoreAddr, 0),
+            "storemem" => (Instruction::StoreMem, 0),
+            "jmpacc" => (Instruction::JmpAcc, 0),
+            "jmp" => (Instruction::Jmp(target(1)?), 1),
+            "jz" => (Instruction::Jz(target(1)?), 1),
+            "jnz" => (Instruction::Jnz(target(1)?), 1),
+            "storetostack" => (Instruction::StoreToStack(offset(1)?), 1),
+            "discard" => (Instruction::Discard(offset(1)?), 1),
+            "popdiscard" => (Instruction::PopDiscard(offset(1)?), 1),
+            "alloc" => (Instruction::Alloc(offset(1)?), 1),
+            "??" => match target(1)? {
+                Target::Absolute(b) => (Instruction::Unknown(b), 1),
+                _ => return Err(ParseError::new(tokens[1].0, "?? needs a hex byte")),
+            },
+            _ => return Err(ParseError::new(column, format!("unknown opcode '{}'", mnemonic))),
+        };
+
+        expect_end(&tokens, operands + 1)?;
+        Ok(instruction)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::StoreAddr => write!(f, "storeaddr"),
+            Instruction::StoreMem => write!(f, "storemem"),
+            Instruction::JmpAcc => write!(f, "jmpacc"),
+            Instruction::Jmp(t) => write!(f, "jmp {}", t),
+            Instruction::Jz(t) => write!(f, "jz {}", t),
+            Instruction::Jnz(t) => write!(f, "jnz {}", t),
+            Instruction::StoreToStack(o) => write!(f, "storetostack {}", o),
+            Instruction::Discard(o) => write!(f, "discard {}", o),
+            Instruction::Alloc(o) => write!(f, "alloc {}", o),
+            Instruction::PopDiscard(o) => write!(f, "popdiscard {}", o),
+            Instruction::WithPush(p) => write!(f, "{} push", p),
+            Instruction::WithoutPush(p) => write!(f, "{}", p),
+            Instruction::Unknown(b) => write!(f, "?? {:02x}", b),
         }
     }
 }
@@ -199,116 +387,304 @@ pub enum Line {
     Macro(String, Vec<Instruction>)
 }
 
+// prints the line back as assembler source
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Label(l) => write!(f, "{}", l),
+            Line::Comment(c) if c.is_empty() || c.starts_with('#') => write!(f, "{}", c),
+            Line::Comment(c) => write!(f, "# {}", c),
+            Line::Instruction(i) => write!(f, "{}", i),
+            Line::Macro(line, _) => write!(f, "{}", line),
+        }
+    }
+}
+
 impl Line {
-    pub fn parse(line: String) -> Line {
-        match line.chars().next() {
-            Some('#') | None => { 
-                Line::Comment(line)
+    // bytes the line takes up in the ROM
+    pub fn get_size(&self) -> usize {
+        match self {
+            Line::Instruction(i) => i.get_size() as usize,
+            Line::Macro(_, instructions) => instructions.iter().map(|i| i.get_size() as usize).sum(),
+            Line::Label(_) | Line::Comment(_) => 0,
+        }
+    }
+
+    pub fn parse(line: String) -> Result<Line, ParseError> {
+        let code = strip_comment(&line);
+        match code.trim_start().chars().next() {
+            None => { 
+                Ok(Line::Comment(line))
             },
             Some(':') => {
-                Line::Label(line)
+                let tokens = tokenize(code);
+                expect_end(&tokens, 1)?;
+                Ok(Line::Label(tokens[0].1.to_owned()))
             },
             Some(_) => {
                 let mut instructions = Vec::new();
 
                 // handle macros
-                let tokens : Vec<&str> = line.split_whitespace().collect();
-                match tokens[0].to_lowercase().as_ref() {
+                let tokens = tokenize(code);
+                let operands = match tokens[0].1.to_lowercase().as_ref() {
                     "call" => {
+                        let target = match tokens.get(1) {
+                            Some((column, token)) => Target::parse(token).map_err(|e| ParseError::new(*column, e))?,
+                            None => return Err(ParseError::new(tokens[0].0 + 4, "call needs a target")),
+                        };
                         instructions.push(Instruction::WithoutPush(PushableInstruction::LoadLo(Target::Offset(4))));
                         instructions.push(Instruction::WithPush(PushableInstruction::LoadHi(Target::Offset(3))));
-                        instructions.push(Instruction::Jmp(Target::Label(tokens[1].to_owned())));
+                        instructions.push(Instruction::Jmp(target));
                         //retuns here
+                        1
                     },
                     "ret" => {
                         instructions.push(Instruction::PopDiscard(StackOffset(0)));
                         instructions.push(Instruction::JmpAcc);
+                        0
                     },
                     "halt" => {
                         instructions.push(Instruction::Jmp(Target::Absolute(0xFF)));
+                        0
                     },
                     _ => {
-                        return Line::Instruction(Instruction::parse(&line));
+                        return Ok(Line::Instruction(Instruction::parse(code)?));
                     }
-                }
-                
-                Line::Macro(line, instructions)
+                };
+
+                expect_end(&tokens, operands + 1)?;
+                Ok(Line::Macro(line, instructions))
             },
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new<S: Into<String>>(column: usize, message: S) -> ParseError {
+        ParseError { column, message: message.into() }
+    }
+}
+
+// an error in assembler source, located by file, 1-based line and column
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    fn new<S: Into<String>>(file: &str, line: usize, column: usize, message: S) -> AsmError {
+        AsmError { file: file.to_owned(), line, column, message: message.into() }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// whitespace separated tokens with their 1-based columns
+fn tokenize(line: &str) -> Vec<(usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                tokens.push((s + 1, &line[s..i]));
+                start = None;
+            },
+            (false, None) => start = Some(i),
+            _ => {},
+        }
+    }
+    if let Some(s) = start {
+        tokens.push((s + 1, &line[s..]));
+    }
+    tokens
+}
+
+fn expect_end(tokens: &[(usize, &str)], count: usize) -> Result<(), ParseError> {
+    match tokens.get(count) {
+        Some((column, token)) => Err(ParseError::new(*column, format!("unexpected '{}'", token))),
+        None => Ok(()),
+    }
+}
+
+// allows "jmp :loop # comment" after an instruction or label
+fn strip_comment(line: &str) -> &str {
+    match line.find('#') {
+        Some(i) => line[..i].trim_end(),
+        None => line,
+    }
+}
 
 trait Resolver {
-    fn resolve(&self, pc: u8, labels: &BTreeMap<&String,u8>) -> Instruction;
+    fn resolve(&self, pc: u8, labels: &BTreeMap<String,u8>) -> Result<Instruction, String>;
 }
 
 impl Resolver for Instruction {
-    fn resolve(&self, pc: u8, labels: &BTreeMap<&String,u8>) -> Instruction {
-        match self {
-            Instruction::WithoutPush(i) => Instruction::WithoutPush(i.resolve_pushable(pc, labels)),
-            Instruction::WithPush(i) => Instruction::WithPush(i.resolve_pushable(pc, labels)),
-            Instruction::Jmp(t) => match t {
-                Target::Absolute(_) => self.clone(),
-                Target::Offset(o) => Instruction::Jmp(Target::Absolute(pc + o)),
-                Target::Label(l) => Instruction::Jmp(Target::Absolute(labels[l])),
-            },
-            Instruction::Jz(t) => match t {
-                Target::Absolute(_) => self.clone(),
-                Target::Offset(o) => Instruction::Jnz(Target::Absolute(pc + o)),
-                Target::Label(l) => Instruction::Jz(Target::Absolute(labels[l]))
-            },
-            Instruction::Jnz(t) => match t {
-                Target::Absolute(_) => self.clone(),
-                Target::Offset(o) => Instruction::Jz(Target::Absolute(pc + o)),
-                Target::Label(l) => Instruction::Jnz(Target::Absolute(labels[l]))
-            },
+    fn resolve(&self, pc: u8, labels: &BTreeMap<String,u8>) -> Result<Instruction, String> {
+        Ok(match self {
+            Instruction::WithoutPush(i) => Instruction::WithoutPush(i.resolve_pushable(pc, labels)?),
+            Instruction::WithPush(i) => Instruction::WithPush(i.resolve_pushable(pc, labels)?),
+            Instruction::Jmp(t) => Instruction::Jmp(Target::Absolute(t.resolve(pc, labels)?)),
+            Instruction::Jz(t) => Instruction::Jz(Target::Absolute(t.resolve(pc, labels)?)),
+            Instruction::Jnz(t) => Instruction::Jnz(Target::Absolute(t.resolve(pc, labels)?)),
             _ => self.clone()
+        })
+    }
+}
+
+// line numbers in errors are 1-based indexes into the source lines
+pub fn parse_lines(file: &str, source: &str) -> Result<Vec<Line>, Vec<AsmError>> {
+    let (lines, errors) = parse_source(file, source);
+    if errors.is_empty() {
+        Ok(lines)
+    } else {
+        Err(errors)
+    }
+}
+
+// a line that does not parse still takes its place in lines so later errors keep their line
+// numbers; one that starts with a label still defines it, so jumps to it are not also reported
+fn parse_source(file: &str, source: &str) -> (Vec<Line>, Vec<AsmError>) {
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    for (n, text) in source.lines().enumerate() {
+        match Line::parse(text.to_owned()) {
+            Ok(line) => lines.push(line),
+            Err(e) => {
+                errors.push(AsmError::new(file, n + 1, e.column, e.message));
+                lines.push(match tokenize(strip_comment(text)).first() {
+                    Some((_, token)) if token.starts_with(':') => Line::Label((*token).to_owned()),
+                    _ => Line::Comment(text.to_owned()),
+                });
+            }
         }
     }
+    (lines, errors)
 }
 
-pub fn assemble(lines: Vec<Line>) -> Vec<Instruction> {
+// the resolved instructions and the address of each label
+pub type Assembled = (Vec<Instruction>, BTreeMap<String, u8>);
 
-    println!("v2.0 raw");
+// parses, lays out and resolves source, reporting the errors from all three together;
+// resolution errors point at the operand that could not be resolved
+pub fn assemble_source(file: &str, source: &str, listing: bool) -> Result<Assembled, Vec<AsmError>> {
+    let (lines, mut errors) = parse_source(file, source);
+    let sources : Vec<&str> = source.lines().collect();
+    let (instructions, labels, assemble_errors) = assemble_lines(file, &lines, &sources, listing && errors.is_empty());
+    errors.extend(assemble_errors);
+    errors.sort_by_key(|e| (e.line, e.column));
 
-    for (i,line) in lines.iter().enumerate() {
-        println!("# Line {}: {:?}", i, line);
+    if errors.is_empty() {
+        Ok((instructions, labels))
+    } else {
+        Err(errors)
     }
+}
 
-    let labels = {
-        let mut labels = BTreeMap::new();
-        let mut address : u8 = 0;
-        for line in &lines {
-            match line {
-                Line::Instruction(i) => { address += i.get_size(); },
-                Line::Label(l) => { 
-                    if let Some(existing) = labels.insert(l, address) {
-                        panic!("label {:?} already exists at {}!", l, existing);
-                    }
-                }
-                Line::Comment(_) => {},
-                Line::Macro(_, instructions) => { 
-                    for i in instructions {
-                        address += i.get_size();
-                    }
-                }
+fn label_table(file: &str, lines: &[Line]) -> (BTreeMap<String, u8>, Vec<AsmError>) {
+    let mut labels = BTreeMap::new();
+    let mut defined_on = BTreeMap::new();
+    let mut errors = Vec::new();
+    let mut address : usize = 0;
+    for (n, line) in lines.iter().enumerate() {
+        if let Line::Label(l) = line {
+            if let Some(first) = defined_on.get(l) {
+                errors.push(AsmError::new(file, n + 1, 1,
+                    format!("label {} is already defined on line {}", l, first)));
+            } else {
+                // a label past the end of the ROM is covered by the one error below; its
+                // wrapped address is never used, but keeps it from being reported as undefined
+                labels.insert(l.clone(), address as u8);
+                defined_on.insert(l, n + 1);
             }
         }
-        labels
-    };
 
+        let size = line.get_size();
+
+        if address <= 0x100 && address + size > 0x100 {
+            errors.push(AsmError::new(file, n + 1, 1, "program does not fit in the 256 byte ROM"));
+        }
+        address += size;
+    }
+    (labels, errors)
+}
+
+// reports every error in lines rather than stopping at the first
+pub fn assemble(file: &str, lines: Vec<Line>) -> Result<Vec<Instruction>, Vec<AsmError>> {
+    let (instructions, _, errors) = assemble_lines(file, &lines, &[], true);
+    if errors.is_empty() {
+        Ok(instructions)
+    } else {
+        Err(errors)
+    }
+}
+
+// sources, when given, is the text of each line
+fn assemble_lines(file: &str, lines: &[Line], sources: &[&str], listing: bool) -> (Vec<Instruction>, BTreeMap<String, u8>, Vec<AsmError>) {
+
+    if listing {
+        println!("v2.0 raw");
+
+        for (i,line) in lines.iter().enumerate() {
+            println!("# Line {}: {:?}", i, line);
+        }
+    }
+
+    let (labels, mut errors) = label_table(file, lines);
 
-    for l in &labels {
-        println!("# {:?}", l);
+    if listing {
+        for l in &labels {
+            println!("# {:?}", l);
+        }
     }
 
-    let rom = {
-        let mut instructions = Vec::new();
-        let mut pc = 0;
+    let (instructions, resolve_errors) = resolve_lines(file, lines, sources, &labels, listing);
+    errors.extend(resolve_errors);
+    errors.sort_by_key(|e| e.line);
+
+    (instructions, labels, errors)
+}
+
+fn resolve_lines(file: &str, lines: &[Line], sources: &[&str], labels: &BTreeMap<String, u8>, listing: bool) -> (Vec<Instruction>, Vec<AsmError>) {
+    let mut instructions = Vec::new();
+    let mut errors = Vec::new();
+    let mut pc : usize = 0;
+
+    let mut resolve_instruction = |n: usize, i: &Instruction| {
+        if pc > 0xff {
+            // already reported by label_table
+            return;
+        }
+
+        let resolved = match i.resolve(pc as u8, labels) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                // point at the operand when there is source to find it in
+                let column = match sources.get(n).map(|text| tokenize(strip_comment(text))) {
+                    Some(tokens) if tokens.len() > 1 => tokens[1].0,
+                    _ => 1,
+                };
+                errors.push(AsmError::new(file, n + 1, column, e));
+                pc += i.get_size() as usize;
+                return;
+            }
+        };
 
-        let mut resolve_instruction = |i: &Instruction| {
-            let resolved = i.resolve(pc, &labels);
+        if listing {
             match resolved.encode() {
                 (first,Some(second)) => {
                     print!("{:02x} {:02x} # @{:02x} {:?}", first, second, pc, i);
@@ -322,61 +698,214 @@ pub fn assemble(lines: Vec<Line>) -> Vec<Instruction> {
                 print!(" {:?}", resolved);
             }
             println!();
-            instructions.push(resolved);
-            pc += i.get_size();
-        };
+        }
+        instructions.push(resolved);
+        pc += i.get_size() as usize;
+    };
 
-        for l in &lines {
-            match l {
-                Line::Instruction(i) => {
-                    resolve_instruction(i);
-                },
-                Line::Macro(line, instructions) => {
+    for (n, l) in lines.iter().enumerate() {
+        match l {
+            Line::Instruction(i) => {
+                resolve_instruction(n, i);
+            },
+            Line::Macro(line, instructions) => {
+                if listing {
                     println!("# begin resolving macro: '{}'", &line);
-                    for i in instructions {
-                        resolve_instruction(i);
-                    }
+                }
+                for i in instructions {
+                    resolve_instruction(n, i);
+                }
+                if listing {
                     println!("# end resolving macro: '{}'", &line);
-                },
-                Line::Label(l) => { 
+                }
+            },
+            Line::Label(l) => { 
+                if listing {
                     println!("# {:?}", l);
-                },
-                Line::Comment(c) => { 
+                }
+            },
+            Line::Comment(c) => { 
+                if listing {
                     println!("# {}", c);
+                }
+            },
+        }
+    }
+    (instructions, errors)
+}
+
+// reads a Logisim "v2.0 raw" image, including "count*value" runs and # comments
+pub fn parse_image(text: &str) -> Result<Vec<u8>, String> {
+    let mut lines = text.lines();
+    match lines.next() {
+        Some(header) if header.trim() == "v2.0 raw" => {},
+        _ => return Err("missing 'v2.0 raw' header".to_owned()),
+    }
+
+    let mut image = Vec::new();
+    for (i, line) in lines.enumerate() {
+        for token in strip_comment(line).split_whitespace() {
+            let (count, value) = match token.find('*') {
+                Some(star) => {
+                    let count = token[..star].parse::<usize>()
+                        .map_err(|_| format!("line {}: invalid run length in '{}'", i + 2, token))?;
+                    (count, &token[star+1..])
                 },
-            }
+                None => (1, token),
+            };
+            let value = u8::from_str_radix(value, 16)
+                .map_err(|_| format!("line {}: invalid byte '{}'", i + 2, token))?;
+            image.extend(std::iter::repeat_n(value, count));
         }
-        instructions
-    };
+    }
+
+    if image.len() > 256 {
+        return Err(format!("image is {} bytes but the ROM only holds 256", image.len()));
+    }
+
+    Ok(image)
+}
+
+pub fn disassemble(rom: &[u8]) -> Vec<(u8, Instruction)> {
+    let mut instructions = Vec::new();
+    let mut pc = 0;
+    while let Some((i, size)) = Instruction::decode(&rom[pc..]) {
+        instructions.push((pc as u8, i));
+        pc += size;
+    }
+    instructions
+}
 
+pub fn encode_rom(insts: &[Instruction]) -> Vec<u8> {
+    let mut rom = Vec::new();
+    for i in insts {
+        match i.encode() {
+            (first, Some(second)) => { rom.push(first); rom.push(second); },
+            (first, None) => rom.push(first),
+        }
+    }
     rom
 }
 
-pub fn simulate(insts: &[Instruction], mut cycle_limit: usize) {
-    let rom = {
-        let mut rom = BTreeMap::new();
-        let mut pc = 0;
-        for i in insts {
-            rom.insert(pc, i);
-            pc += i.get_size();
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    // PC reached 0xFF (see the halt macro)
+    Halted,
+    CycleLimit,
+    // PC (or a jump's target byte) is outside the ROM image
+    BadAddress(u8),
+    IllegalInstruction { pc: u8, opcode: u8 },
+}
+
+// runs the encoded image exactly as the ROM would, decoding each byte at fetch time
+pub struct Machine {
+    rom: Vec<u8>,
+    mem: [Wrapping<u8>; 256],
+    regs: [Wrapping<u8>; 5],
+    cycles: usize,
+    pub trace: bool,
+    peripherals: Vec<Box<dyn Peripheral>>,
+}
+
+impl Machine {
+    pub fn new(rom: &[u8]) -> Machine {
+        assert!(rom.len() <= 256, "image does not fit in ROM");
+        Machine {
+            rom: rom.to_vec(),
+            mem: [Wrapping(0u8); 256],
+            regs: [Wrapping(0u8); 5],
+            cycles: 0,
+            trace: false,
+            peripherals: Vec::new(),
         }
-        rom
-    };
+    }
 
-    let mut mem = [Wrapping(0u8); 256];
-    let mut regs = [Wrapping(0u8); 5];
+    // addresses no peripheral answers to are plain RAM
+    pub fn attach(&mut self, peripheral: Box<dyn Peripheral>) -> Result<(), String> {
+        let range = peripheral.range();
+        if let Some(other) = self.peripherals.iter().find(|p| range.start() <= p.range().end() && p.range().start() <= range.end()) {
+            return Err(format!("{} at {:02x}-{:02x} overlaps {} at {:02x}-{:02x}",
+                peripheral.name(), range.start(), range.end(), other.name(), other.range().start(), other.range().end()));
+        }
+        self.peripherals.push(peripheral);
+        Ok(())
+    }
 
-    println!("# begin simulation");
-    let mut cycles = 0;
-    while cycle_limit != cycles && regs[Reg::PC as usize].0 != 0xFF {
-        cycles += 1;
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn reg(&self, r: Reg) -> u8 {
+        self.regs[r as usize].0
+    }
+
+    pub fn set_reg(&mut self, r: Reg, value: u8) {
+        self.regs[r as usize] = Wrapping(value);
+    }
 
-        let instruction = rom.get(&regs[Reg::PC as usize].0).expect("bad instruction address.");
-        print!("# PC:{:02x} {:?}", regs[Reg::PC as usize], instruction);
-        print!(" regs:{:?} stack:{:?}", regs, &mem[(regs[Reg::SP as usize].0 as usize)..]);
+    pub fn mem(&self, addr: u8) -> u8 {
+        self.mem[addr as usize].0
+    }
+
+    pub fn set_mem(&mut self, addr: u8, value: u8) {
+        self.mem[addr as usize] = Wrapping(value);
+    }
+
+    // everything from SP to the top of memory
+    pub fn stack(&self) -> Vec<u8> {
+        self.mem[self.reg(Reg::SP) as usize..].iter().map(|m| m.0).collect()
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn halted(&self) -> bool {
+        self.reg(Reg::PC) == 0xFF
+    }
+
+    // decodes the instruction at PC without executing it
+    pub fn fetch(&self) -> Result<(Instruction, usize), StopReason> {
+        let pc = self.reg(Reg::PC);
+        let (instruction, size) = match self.rom.get(pc as usize..).and_then(Instruction::decode) {
+            Some(decoded) => decoded,
+            None => return Err(StopReason::BadAddress(pc)),
+        };
 
-        let mut bump_pc = 1;
         match instruction {
+            // a jump whose target byte is past the end of the image
+            Instruction::Unknown(opcode) if matches!(Instruction::decode(&[opcode, 0]), Some((_, 2))) => {
+                Err(StopReason::BadAddress(pc.wrapping_add(1)))
+            },
+            Instruction::Unknown(opcode) => Err(StopReason::IllegalInstruction { pc, opcode }),
+            _ => Ok((instruction, size)),
+        }
+    }
+
+    // executes one instruction; returns why the machine can't continue, if it can't
+    pub fn step(&mut self) -> Option<StopReason> {
+        if self.halted() {
+            return Some(StopReason::Halted);
+        }
+
+        let (instruction, size) = match self.fetch() {
+            Ok(fetched) => fetched,
+            Err(reason) => return Some(reason),
+        };
+
+        self.cycles += 1;
+
+        let regs = &mut self.regs;
+        let mem = &mut self.mem;
+        let peripherals = &mut self.peripherals;
+
+        if self.trace {
+            print!("# PC:{:02x} {:?}", regs[Reg::PC as usize], instruction);
+            print!(" regs:{:?} stack:{:?}", regs, &mem[(regs[Reg::SP as usize].0 as usize)..]);
+        }
+
+        let mut bump_pc = size as u8;
+        match &instruction {
             Instruction::WithPush(i) | Instruction::WithoutPush(i) => {
                 match i {
                     PushableInstruction::LoadLo(t) => match t {
@@ -396,25 +925,31 @@ pub fn simulate(insts: &[Instruction], mut cycle_limit: usize) {
                         _ => unreachable!()
                     },
                     PushableInstruction::Add(offset) => {
-                        let stack_value = mem[regs[Reg::SP as usize].0 as usize + offset.0 as usize];
+                        let stack_value = mem[stack_index(regs, offset)];
                         let sum = (regs[Reg::ACC as usize].0 as u16) + stack_value.0 as u16;
                         regs[Reg::ACC as usize] = Wrapping((sum & 0xff) as u8);
                         regs[Reg::FLAGS as usize] = Wrapping((sum >> 8) as u8);
                     },
-                    PushableInstruction::Xor(offset) => regs[Reg::ACC as usize] ^= mem[regs[Reg::SP as usize].0 as usize + offset.0 as usize],
-                    PushableInstruction::Not(offset) => regs[Reg::ACC as usize] = !mem[regs[Reg::SP as usize].0 as usize + offset.0 as usize],
-                    PushableInstruction::Or(offset) => regs[Reg::ACC as usize] |= mem[regs[Reg::SP as usize].0 as usize + offset.0 as usize],
-                    PushableInstruction::And(offset) => regs[Reg::ACC as usize] &= mem[regs[Reg::SP as usize].0 as usize + offset.0 as usize],
+                    PushableInstruction::Xor(offset) => regs[Reg::ACC as usize] ^= mem[stack_index(regs, offset)],
+                    PushableInstruction::Not(offset) => regs[Reg::ACC as usize] = !mem[stack_index(regs, offset)],
+                    PushableInstruction::Or(offset) => regs[Reg::ACC as usize] |= mem[stack_index(regs, offset)],
+                    PushableInstruction::And(offset) => regs[Reg::ACC as usize] &= mem[stack_index(regs, offset)],
                     PushableInstruction::Mul(offset) => {
-                        let stack_value = mem[regs[Reg::SP as usize].0 as usize + offset.0 as usize];
+                        let stack_value = mem[stack_index(regs, offset)];
                         regs[Reg::ACC as usize] *= stack_value;
                     },
-                    PushableInstruction::LoadFromStack(offset) => regs[Reg::ACC as usize] = mem[regs[Reg::SP as usize].0 as usize + offset.0 as usize],
-                    PushableInstruction::LoadMem => regs[Reg::ACC as usize] = mem[regs[Reg::ADDR as usize].0 as usize],
+                    PushableInstruction::LoadFromStack(offset) => regs[Reg::ACC as usize] = mem[stack_index(regs, offset)],
+                    PushableInstruction::LoadMem => {
+                        let addr = regs[Reg::ADDR as usize].0;
+                        regs[Reg::ACC as usize] = match peripheral(peripherals, addr) {
+                            Some(p) => Wrapping(p.read(addr)),
+                            None => mem[addr as usize],
+                        };
+                    },
                     PushableInstruction::LoadPc => regs[Reg::ACC as usize] = regs[Reg::PC as usize],
                 }
 
-                if let Instruction::WithPush(_) = instruction {
+                if let Instruction::WithPush(_) = &instruction {
                     regs[Reg::SP as usize] += Wrapping(0xff);
                     mem[regs[Reg::SP as usize].0 as usize] = regs[Reg::ACC as usize];
                 }
@@ -423,7 +958,11 @@ pub fn simulate(insts: &[Instruction], mut cycle_limit: usize) {
                 regs[Reg::ADDR as usize] = regs[Reg::ACC as usize];
             },
             Instruction::StoreMem => {
-                mem[regs[Reg::ADDR as usize].0 as usize]= regs[Reg::ACC as usize];
+                let addr = regs[Reg::ADDR as usize].0;
+                match peripheral(peripherals, addr) {
+                    Some(p) => p.write(addr, regs[Reg::ACC as usize].0),
+                    None => mem[addr as usize] = regs[Reg::ACC as usize],
+                }
             },
             Instruction::JmpAcc => {
                 bump_pc = 0;
@@ -441,8 +980,6 @@ pub fn simulate(insts: &[Instruction], mut cycle_limit: usize) {
                     if regs[Reg::ACC as usize].0 == 0 {
                         bump_pc = 0;
                         regs[Reg::PC as usize] = Wrapping(*c);
-                    } else {
-                        bump_pc = 2;
                     }
                 },
                 _ => unreachable!()
@@ -452,14 +989,12 @@ pub fn simulate(insts: &[Instruction], mut cycle_limit: usize) {
                     if regs[Reg::ACC as usize].0 != 0 {
                         bump_pc = 0;
                         regs[Reg::PC as usize] = Wrapping(*c);
-                    } else {
-                        bump_pc = 2;
                     }
                 },
                 _ => unreachable!()
             },
             Instruction::StoreToStack(offset) => {
-                mem[(regs[Reg::SP as usize] + Wrapping(offset.0)).0 as usize] = regs[Reg::ACC as usize];
+                mem[stack_index(regs, offset)] = regs[Reg::ACC as usize];
             },
             Instruction::Discard(offset) => {
                 regs[Reg::SP as usize] += Wrapping(offset.0);
@@ -470,20 +1005,190 @@ pub fn simulate(insts: &[Instruction], mut cycle_limit: usize) {
             Instruction::PopDiscard(offset) => {
                 regs[Reg::ACC as usize] = mem[regs[Reg::SP as usize].0 as usize];
                 regs[Reg::SP as usize] += Wrapping(offset.0 + 1);
-            }
-            
+            },
+            Instruction::Unknown(_) => unreachable!(),
         }
 
-        println!(" regs:{:?} stack:{:?}", regs, &mem[(regs[Reg::SP as usize].0 as usize)..]);
+        if self.trace {
+            println!(" regs:{:?} stack:{:?}", regs, &mem[(regs[Reg::SP as usize].0 as usize)..]);
+        }
         regs[Reg::PC as usize] += Wrapping(bump_pc);
 
+        for p in peripherals.iter_mut() {
+            p.tick();
+        }
+
+        None
     }
 
-    
-    if cycle_limit == cycles {
-        println!("# simulation timed out after {} cycles", cycles);
-    } else {
-        println!("# simulation completed after {} cycles", cycles);
+    // runs for at most cycle_limit more cycles
+    pub fn run(&mut self, cycle_limit: usize) -> StopReason {
+        let start = self.cycles;
+        loop {
+            if self.halted() {
+                return StopReason::Halted;
+            }
+            if self.cycles - start == cycle_limit {
+                return StopReason::CycleLimit;
+            }
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
     }
 }
 
+fn peripheral(peripherals: &mut [Box<dyn Peripheral>], addr: u8) -> Option<&mut Box<dyn Peripheral>> {
+    peripherals.iter_mut().find(|p| p.range().contains(&addr))
+}
+
+fn stack_index(regs: &[Wrapping<u8>; 5], offset: &StackOffset) -> usize {
+    (regs[Reg::SP as usize] + Wrapping(offset.0)).0 as usize
+}
+
+pub fn simulate(image: &[u8], cycle_limit: usize, trace: bool, peripherals: Vec<Box<dyn Peripheral>>) -> Result<Machine, String> {
+    let mut machine = Machine::new(image);
+    machine.trace = trace;
+    for p in peripherals {
+        machine.attach(p)?;
+    }
+
+    println!("# begin simulation");
+    match machine.run(cycle_limit) {
+        StopReason::Halted => println!("# simulation completed after {} cycles", machine.cycles()),
+        StopReason::CycleLimit => println!("# simulation timed out after {} cycles", machine.cycles()),
+        reason => println!("# simulation stopped after {} cycles: {:?}", machine.cycles(), reason),
+    }
+    println!("# regs:{:?}", machine.regs);
+    Ok(machine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for opcode in 0..=0xffu8 {
+            let bytes = [opcode, 0x42];
+            let (instruction, size) = Instruction::decode(&bytes).unwrap();
+            assert_eq!(size, instruction.get_size() as usize);
+
+            let encoded = match instruction.encode() {
+                (first, Some(second)) => vec![first, second],
+                (first, None) => vec![first],
+            };
+            assert_eq!(&bytes[..size], &encoded[..], "{:02x} {}", opcode, instruction);
+
+            match Line::parse(instruction.to_string()) {
+                Ok(Line::Instruction(parsed)) => assert_eq!(instruction, parsed, "{:02x}", opcode),
+                other => panic!("{:02x} {} parsed as {:?}", opcode, instruction, other),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_jump_decodes_as_unknown() {
+        assert_eq!(Some((Instruction::Unknown(0x3c), 1)), Instruction::decode(&[0x3c]));
+        assert_eq!(None, Instruction::decode(&[]));
+    }
+
+    #[test]
+    fn disassembly_reassembles_to_the_same_rom() {
+        let (instructions, _) = assemble_source("fac.asm", include_str!("../../fac.asm"), false).unwrap();
+        let rom = encode_rom(&instructions);
+        let listing : String = disassemble(&rom).iter().map(|(_, i)| format!("{}\n", i)).collect();
+        let (reassembled, _) = assemble_source("<listing>", &listing, false).unwrap();
+        assert_eq!(rom, encode_rom(&reassembled));
+    }
+
+    #[test]
+    fn errors_from_every_phase_are_reported_together() {
+        let source = ":start\n  loadlo :nowhere\n  bogus 1\n:start\n  jz  :missing  # x\n:bad extra\n  jmp :bad\n";
+        let errors : Vec<String> = assemble_source("t.asm", source, false).unwrap_err().iter().map(|e| e.to_string()).collect();
+        assert_eq!(vec![
+            "t.asm:2:10: undefined label :nowhere",
+            "t.asm:3:3: unknown opcode 'bogus'",
+            "t.asm:4:1: label :start is already defined on line 1",
+            "t.asm:5:7: undefined label :missing",
+            "t.asm:6:6: unexpected 'extra'",
+        ], errors);
+    }
+
+    #[test]
+    fn an_oversized_program_is_one_error() {
+        let source = format!("  jmp :end\n{}:end\n  jz :end\n", "  not 0\n".repeat(300));
+        let errors : Vec<String> = assemble_source("t.asm", &source, false).unwrap_err().iter().map(|e| e.to_string()).collect();
+        assert_eq!(vec!["t.asm:256:1: program does not fit in the 256 byte ROM"], errors);
+    }
+
+    fn assembled(source: &str) -> Machine {
+        let (instructions, _) = assemble_source("<test>", source, false).unwrap();
+        Machine::new(&encode_rom(&instructions))
+    }
+
+    #[test]
+    fn run_halts_with_the_result_in_acc() {
+        // 5 + 4 + 3 + 2 + 1
+        let mut machine = assembled("
+            loadlo 0 push   # total
+            loadlo 5 push   # n
+            :loop
+            loadfromstack 0
+            jz :done
+            add 1
+            storetostack 1
+            loadlo f
+            loadhi f
+            add 0
+            storetostack 0
+            jmp :loop
+            :done
+            loadfromstack 1
+            halt
+        ");
+        assert_eq!(StopReason::Halted, machine.run(1000));
+        assert_eq!(15, machine.reg(Reg::ACC));
+        assert_eq!(0xfe, machine.reg(Reg::SP));
+        assert!(machine.halted());
+    }
+
+    #[test]
+    fn run_stops_at_the_cycle_limit_and_resumes() {
+        let mut machine = assembled(":spin\njmp :spin");
+        assert_eq!(StopReason::CycleLimit, machine.run(100));
+        assert_eq!(100, machine.cycles());
+        assert_eq!(StopReason::CycleLimit, machine.run(50));
+        assert_eq!(150, machine.cycles());
+    }
+
+    #[test]
+    fn run_reports_illegal_instructions_and_bad_addresses() {
+        let mut machine = assembled("loadlo 1\n?? 30");
+        assert_eq!(StopReason::IllegalInstruction { pc: 1, opcode: 0x30 }, machine.run(10));
+        assert_eq!(1, machine.reg(Reg::ACC));
+
+        let mut machine = assembled("jmp 40");
+        assert_eq!(StopReason::BadAddress(0x40), machine.run(10));
+    }
+
+    #[test]
+    fn peripherals_replace_memory_in_their_range() {
+        let mut machine = assembled("
+            loadlo 4
+            loadhi f
+            storeaddr
+            loadlo 5
+            storemem
+            loadmem
+            halt
+        ");
+        machine.attach(Box::new(Leds::new(0xf4, Box::new(std::io::sink())))).unwrap();
+        assert_eq!(StopReason::Halted, machine.run(100));
+        assert_eq!(5, machine.reg(Reg::ACC));
+        assert_eq!(0, machine.mem(0xf4));
+
+        let overlap = machine.attach(Box::new(Timer::new(0xf3, 1))).unwrap_err();
+        assert_eq!("timer at f3-f4 overlaps leds at f4-f4", overlap);
+    }
+}
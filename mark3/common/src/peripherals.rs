@@ -0,0 +1,454 @@
+use std::fs;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use toml::value::{Table, Value};
+
+// a device on the memory bus: LoadMem and StoreMem at an address in its range reach it
+// instead of RAM, and it is ticked once per machine cycle
+pub trait Peripheral {
+    fn name(&self) -> &'static str;
+    fn range(&self) -> RangeInclusive<u8>;
+    fn read(&mut self, addr: u8) -> u8;
+    fn write(&mut self, addr: u8, value: u8);
+    fn tick(&mut self) {}
+}
+
+// base: a count that goes up once every period cycles; writing sets it.
+// base+1: 1 if the count has wrapped to 0 since it was last read, else 0
+pub struct Timer {
+    base: u8,
+    period: u64,
+    cycles: u64,
+    count: u8,
+    wrapped: bool,
+}
+
+impl Timer {
+    pub fn new(base: u8, period: u64) -> Timer {
+        Timer { base, period, cycles: 0, count: 0, wrapped: false }
+    }
+}
+
+impl Peripheral for Timer {
+    fn name(&self) -> &'static str {
+        "timer"
+    }
+
+    fn range(&self) -> RangeInclusive<u8> {
+        self.base..=self.base + 1
+    }
+
+    fn read(&mut self, addr: u8) -> u8 {
+        if addr == self.base {
+            self.count
+        } else {
+            std::mem::replace(&mut self.wrapped, false) as u8
+        }
+    }
+
+    fn write(&mut self, addr: u8, value: u8) {
+        if addr == self.base {
+            self.count = value;
+        }
+    }
+
+    fn tick(&mut self) {
+        self.cycles += 1;
+        if self.cycles == self.period {
+            self.cycles = 0;
+            self.count = self.count.wrapping_add(1);
+            self.wrapped |= self.count == 0;
+        }
+    }
+}
+
+// a row of 8 LEDs, bit 7 leftmost; shown on output whenever they change
+pub struct Leds {
+    address: u8,
+    value: u8,
+    output: Box<dyn Write>,
+}
+
+impl Leds {
+    pub fn new(address: u8, output: Box<dyn Write>) -> Leds {
+        Leds { address, value: 0, output }
+    }
+}
+
+impl Peripheral for Leds {
+    fn name(&self) -> &'static str {
+        "leds"
+    }
+
+    fn range(&self) -> RangeInclusive<u8> {
+        self.address..=self.address
+    }
+
+    fn read(&mut self, _addr: u8) -> u8 {
+        self.value
+    }
+
+    fn write(&mut self, _addr: u8, value: u8) {
+        if value != self.value {
+            self.value = value;
+            // as with the console, a closed output just drops what is shown
+            let _ = writeln!(self.output, "# leds {:08b}", value);
+        }
+    }
+}
+
+// 7-segment digits that each show the low nibble of their byte in hex, the digit at the
+// base address leftmost; shown on output whenever they change
+pub struct Display {
+    base: u8,
+    digits: Vec<u8>,
+    output: Box<dyn Write>,
+}
+
+impl Display {
+    pub fn new(base: u8, digits: usize, output: Box<dyn Write>) -> Display {
+        Display { base, digits: vec![0; digits], output }
+    }
+}
+
+impl Peripheral for Display {
+    fn name(&self) -> &'static str {
+        "display"
+    }
+
+    fn range(&self) -> RangeInclusive<u8> {
+        self.base..=self.base + (self.digits.len() - 1) as u8
+    }
+
+    fn read(&mut self, addr: u8) -> u8 {
+        self.digits[(addr - self.base) as usize]
+    }
+
+    fn write(&mut self, addr: u8, value: u8) {
+        let digit = &mut self.digits[(addr - self.base) as usize];
+        if *digit & 0xF != value & 0xF {
+            *digit = value;
+            let shown : String = self.digits.iter().map(|d| format!("{:X}", d & 0xF)).collect();
+            let _ = writeln!(self.output, "# display {}", shown);
+        } else {
+            *digit = value;
+        }
+    }
+}
+
+// reads as the character of the key held down, or 0 when none is. The keys are pressed in
+// turn, each held for hold cycles after a release of the same length.
+pub struct Keypad {
+    address: u8,
+    keys: Vec<u8>,
+    hold: u64,
+    cycles: u64,
+}
+
+impl Keypad {
+    pub fn new(address: u8, keys: &str, hold: u64) -> Keypad {
+        Keypad { address, keys: keys.bytes().collect(), hold, cycles: 0 }
+    }
+}
+
+impl Peripheral for Keypad {
+    fn name(&self) -> &'static str {
+        "keypad"
+    }
+
+    fn range(&self) -> RangeInclusive<u8> {
+        self.address..=self.address
+    }
+
+    fn read(&mut self, _addr: u8) -> u8 {
+        let slot = self.cycles / self.hold;
+        match self.keys.get((slot / 2) as usize) {
+            Some(key) if slot % 2 == 1 => *key,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _addr: u8, _value: u8) {}
+
+    fn tick(&mut self) {
+        self.cycles += 1;
+    }
+}
+
+// the kinds of peripheral that can be configured and the settings each takes
+// besides kind and address
+const KINDS : &[(&str, &[&str])] = &[
+    ("timer", &["period"]),
+    ("leds", &[]),
+    ("display", &["digits"]),
+    ("keypad", &["keys", "hold"]),
+];
+
+// the peripherals given by --peripheral=kind@address[,setting=value]* options (the address in
+// hex, e.g. --peripheral=timer@f2,period=100) and by --peripherals=file.toml, which lists them
+// as [[peripheral]] tables with the same settings. The LEDs and display show on stdout.
+pub fn configure<I: Iterator<Item = String>>(args: I) -> Result<Vec<Box<dyn Peripheral>>, String> {
+    let mut peripherals = Vec::new();
+    for arg in args {
+        if let Some(spec) = arg.strip_prefix("--peripheral=") {
+            let settings = parse_spec(spec).map_err(|e| format!("--peripheral={}: {}", spec, e))?;
+            peripherals.push(build(&settings).map_err(|e| format!("--peripheral={}: {}", spec, e))?);
+        } else if let Some(path) = arg.strip_prefix("--peripherals=") {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            for settings in parse_file(&text).map_err(|e| format!("{}: {}", path, e))? {
+                peripherals.push(build(&settings).map_err(|e| format!("{}: {}", path, e))?);
+            }
+        }
+    }
+    Ok(peripherals)
+}
+
+fn parse_spec(spec: &str) -> Result<Table, String> {
+    let mut parts = spec.split(',');
+    let mut settings = Table::new();
+
+    let (kind, address) = match parts.next().unwrap().split_once('@') {
+        Some(device) => device,
+        None => return Err("expected kind@address".to_owned()),
+    };
+    let address = u8::from_str_radix(address, 16).map_err(|_| format!("invalid address '{}'", address))?;
+    settings.insert("kind".to_owned(), Value::String(kind.to_owned()));
+    settings.insert("address".to_owned(), Value::Integer(address as i64));
+
+    for part in parts {
+        let (key, value) = match part.split_once('=') {
+            Some(setting) => setting,
+            None => return Err(format!("expected setting=value, found '{}'", part)),
+        };
+        // numbers are told apart when they are used, so keys=123 is still a string
+        settings.insert(key.to_owned(), Value::String(value.to_owned()));
+    }
+    Ok(settings)
+}
+
+fn parse_file(text: &str) -> Result<Vec<Table>, String> {
+    let config : Value = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+    let not_tables = || "peripheral must be an array of tables, i.e. [[peripheral]]".to_owned();
+    match config.get("peripheral") {
+        Some(Value::Array(tables)) => tables.iter().map(|t| t.as_table().cloned().ok_or_else(not_tables)).collect(),
+        Some(_) => Err(not_tables()),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn build(settings: &Table) -> Result<Box<dyn Peripheral>, String> {
+    let kind = match settings.get("kind") {
+        Some(Value::String(kind)) => kind.as_str(),
+        _ => return Err("a peripheral needs a kind".to_owned()),
+    };
+    let allowed = match KINDS.iter().find(|(k, _)| *k == kind) {
+        Some((_, allowed)) => allowed,
+        None => {
+            let kinds : Vec<&str> = KINDS.iter().map(|(k, _)| *k).collect();
+            return Err(format!("unknown peripheral '{}'; expected one of {}", kind, kinds.join(", ")));
+        },
+    };
+    if let Some(key) = settings.keys().find(|k| *k != "kind" && *k != "address" && !allowed.contains(&k.as_str())) {
+        return Err(format!("{} has no setting '{}'", kind, key));
+    }
+
+    let address = |last| integer(settings, "address", None, 0..=last).map(|a| a as u8);
+    Ok(match kind {
+        "timer" => Box::new(Timer::new(address(0xfe)?, integer(settings, "period", Some(1), 1..=i64::MAX)? as u64)),
+        "leds" => Box::new(Leds::new(address(0xff)?, Box::new(io::stdout()))),
+        "display" => {
+            let address = address(0xff)?;
+            let digits = integer(settings, "digits", Some(4), 1..=(0x100 - address as i64))?;
+            Box::new(Display::new(address, digits as usize, Box::new(io::stdout())))
+        },
+        "keypad" => {
+            let keys = match settings.get("keys") {
+                Some(Value::String(keys)) => keys.as_str(),
+                Some(_) => return Err("keys must be a string".to_owned()),
+                None => "",
+            };
+            Box::new(Keypad::new(address(0xff)?, keys, integer(settings, "hold", Some(100), 1..=i64::MAX)? as u64))
+        },
+        _ => unreachable!(),
+    })
+}
+
+fn integer(settings: &Table, key: &str, default: Option<i64>, range: RangeInclusive<i64>) -> Result<i64, String> {
+    let n = match (settings.get(key), default) {
+        (Some(Value::Integer(n)), _) => *n,
+        (Some(Value::String(s)), _) if s.parse::<i64>().is_ok() => s.parse().unwrap(),
+        (Some(_), _) => return Err(format!("{} must be a number", key)),
+        (None, Some(n)) => n,
+        (None, None) => return Err(format!("missing setting '{}'", key)),
+    };
+    if range.contains(&n) {
+        Ok(n)
+    } else {
+        Err(format!("{} must be between {} and {}", key, range.start(), range.end()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // what the LEDs or display showed
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn configured(args: &[&str]) -> Result<Vec<(&'static str, RangeInclusive<u8>)>, String> {
+        let peripherals = configure(args.iter().map(|a| a.to_string()))?;
+        Ok(peripherals.iter().map(|p| (p.name(), p.range())).collect())
+    }
+
+    #[test]
+    fn a_spec_configures_a_peripheral() {
+        assert_eq!(Ok(vec![("timer", 0xf2..=0xf3), ("display", 0xf8..=0xfb), ("leds", 0xff..=0xff)]), configured(&[
+            "program.j",
+            "--trace",
+            "--peripheral=timer@f2,period=100",
+            "--peripheral=display@f8",
+            "--peripheral=leds@ff",
+        ]));
+        assert_eq!(Ok(vec![("keypad", 0xf4..=0xf4)]), configured(&["--peripheral=keypad@f4,keys=123,hold=5"]));
+    }
+
+    #[test]
+    fn an_invalid_spec_is_an_error() {
+        for (spec, error) in &[
+            ("--peripheral=timer", "--peripheral=timer: expected kind@address"),
+            ("--peripheral=timer@", "--peripheral=timer@: invalid address ''"),
+            ("--peripheral=timer@f2x", "--peripheral=timer@f2x: invalid address 'f2x'"),
+            ("--peripheral=timer@f2,period", "--peripheral=timer@f2,period: expected setting=value, found 'period'"),
+            ("--peripheral=timer@f2,period=often", "--peripheral=timer@f2,period=often: period must be a number"),
+        ] {
+            assert_eq!(Err(error.to_string()), configured(&[spec]));
+        }
+    }
+
+    #[test]
+    fn an_unknown_kind_or_setting_is_an_error() {
+        assert_eq!(Err("--peripheral=lamp@f2: unknown peripheral 'lamp'; expected one of timer, leds, display, keypad".to_owned()),
+            configured(&["--peripheral=lamp@f2"]));
+        assert_eq!(Err("--peripheral=leds@f2,colour=red: leds has no setting 'colour'".to_owned()),
+            configured(&["--peripheral=leds@f2,colour=red"]));
+        assert_eq!(Err("--peripheral=timer@f2,digits=2: timer has no setting 'digits'".to_owned()),
+            configured(&["--peripheral=timer@f2,digits=2"]));
+    }
+
+    #[test]
+    fn out_of_range_settings_are_errors() {
+        for (spec, error) in &[
+            // the timer's wrapped flag is at address + 1
+            ("timer@ff", "address must be between 0 and 254"),
+            ("timer@f2,period=0", "period must be between 1 and 9223372036854775807"),
+            ("display@f8,digits=0", "digits must be between 1 and 8"),
+            ("display@f8,digits=9", "digits must be between 1 and 8"),
+            ("display@ff,digits=2", "digits must be between 1 and 1"),
+            ("keypad@f4,hold=0", "hold must be between 1 and 9223372036854775807"),
+        ] {
+            let arg = format!("--peripheral={}", spec);
+            assert_eq!(Err(format!("{}: {}", arg, error)), configured(&[&arg]));
+        }
+        assert_eq!(Ok(vec![("display", 0xf8..=0xff)]), configured(&["--peripheral=display@f8,digits=8"]));
+    }
+
+    #[test]
+    fn a_toml_file_lists_peripherals() {
+        let config = "
+            [[peripheral]]
+            kind = \"timer\"
+            address = 0xf2
+            period = 100
+
+            [[peripheral]]
+            kind = \"keypad\"
+            address = 0xf4
+            keys = \"123\"
+        ";
+        let tables = parse_file(config).unwrap();
+        let peripherals : Vec<(&str, RangeInclusive<u8>)> = tables.iter()
+            .map(|t| build(t).map(|p| (p.name(), p.range())))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(vec![("timer", 0xf2..=0xf3), ("keypad", 0xf4..=0xf4)], peripherals);
+
+        assert_eq!(Ok(Vec::new()), parse_file("# nothing attached"));
+        assert_eq!(Err("peripheral must be an array of tables, i.e. [[peripheral]]".to_owned()),
+            parse_file("[peripheral]\nkind = \"leds\""));
+        let tables = parse_file("[[peripheral]]\nkind = \"leds\"\naddress = 256").unwrap();
+        assert_eq!(Some("address must be between 0 and 255".to_owned()), build(&tables[0]).err());
+        let tables = parse_file("[[peripheral]]\naddress = 1").unwrap();
+        assert_eq!(Some("a peripheral needs a kind".to_owned()), build(&tables[0]).err());
+    }
+
+    #[test]
+    fn a_toml_file_is_read_by_configure() {
+        let path = std::env::temp_dir().join(format!("peripherals-{}.toml", std::process::id()));
+        fs::write(&path, "[[peripheral]]\nkind = \"leds\"\naddress = 0xf5\n").unwrap();
+        let arg = format!("--peripherals={}", path.display());
+        let result = configured(&[&arg]);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Ok(vec![("leds", 0xf5..=0xf5)]), result);
+
+        let missing = configured(&["--peripherals=/nonexistent/peripherals.toml"]).unwrap_err();
+        assert!(missing.starts_with("/nonexistent/peripherals.toml: "), "{}", missing);
+    }
+
+    #[test]
+    fn the_timer_counts_and_its_wrapped_flag_clears_on_read() {
+        let mut timer = Timer::new(0xf2, 3);
+        for _ in 0..6 {
+            timer.tick();
+        }
+        assert_eq!(2, timer.read(0xf2));
+        assert_eq!(0, timer.read(0xf3));
+
+        timer.write(0xf2, 0xff);
+        for _ in 0..3 {
+            timer.tick();
+        }
+        assert_eq!(0, timer.read(0xf2));
+        assert_eq!(1, timer.read(0xf3));
+        assert_eq!(0, timer.read(0xf3));
+    }
+
+    #[test]
+    fn leds_and_display_show_changes_on_their_output() {
+        let output = Output::default();
+        let mut leds = Leds::new(0xff, Box::new(output.clone()));
+        leds.write(0xff, 0x81);
+        leds.write(0xff, 0x81);
+        leds.write(0xff, 0x03);
+        assert_eq!(3, leds.read(0xff));
+        assert_eq!("# leds 10000001\n# leds 00000011\n", output.text());
+
+        let output = Output::default();
+        let mut display = Display::new(0xf8, 2, Box::new(output.clone()));
+        display.write(0xf9, 0x2a);
+        // only the low nibble is shown, so this changes nothing visible
+        display.write(0xf9, 0x1a);
+        display.write(0xf8, 0x07);
+        assert_eq!(0x1a, display.read(0xf9));
+        assert_eq!("# display 0A\n# display 7A\n", output.text());
+    }
+}
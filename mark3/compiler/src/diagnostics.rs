@@ -0,0 +1,52 @@
+use std::fmt;
+
+use pest::error::{Error, ErrorVariant};
+
+use crate::Rule;
+
+// byte offsets into the source; kept instead of pest::Span so the AST doesn't borrow the input
+#[derive(Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn of(pair: &pest::iterators::Pair<Rule>) -> Span {
+        let span = pair.as_span();
+        Span { start: span.start(), end: span.end() }
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new<S: Into<String>>(span: Span, message: S) -> Diagnostic {
+        Diagnostic { span, message: message.into() }
+    }
+
+    // line/column plus the offending source line, in the same format as pest's syntax errors
+    pub fn render(&self, path: &str, input: &str) -> String {
+        let span = pest::Span::new(input, self.span.start, self.span.end)
+            .expect("diagnostic span is outside of the input");
+        let variant = ErrorVariant::CustomError { message: self.message.clone() };
+        Error::<Rule>::new_from_span(variant, span).with_path(path).to_string()
+    }
+}
+
+pub fn report(path: &str, input: &str, diagnostics: &[Diagnostic]) {
+    for d in diagnostics {
+        eprintln!("error{}\n", d.render(path, input));
+    }
+    eprintln!("{} error(s)", diagnostics.len());
+}
@@ -3,15 +3,25 @@ extern crate pest;
 extern crate pest_derive;
 
 use pest::Parser;
+use pest::iterators::Pairs;
 
+use std::fs;
 use std::io;
 use std::io::ErrorKind;
 use std::io::Read;
 use std::collections::{BTreeMap,BTreeSet};
+use std::fmt;
+use std::iter::Peekable;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 
 use common::*;
 
+mod diagnostics;
+use diagnostics::{Diagnostic, Span};
+
+mod runtime;
+
 #[derive(Parser)]
 #[grammar = "j.pest"]
 struct ProgramParser;
@@ -21,9 +31,21 @@ enum Operator {
     Add,
     Subtract,
     Multiply,
+    Divide,
+    Modulo,
+    ShiftLeft,
+    ShiftRight,
     Or,
+    And,
+    BitOr,
+    BitAnd,
+    BitXor,
     Equals,
-    NotEquals
+    NotEquals,
+    LessThan,
+    GreaterThan,
+    LessOrEqual,
+    GreaterOrEqual,
 }
 
 impl Operator {
@@ -32,12 +54,287 @@ impl Operator {
             "+" => Operator::Add,
             "-" => Operator::Subtract,
             "*" => Operator::Multiply,
+            "/" => Operator::Divide,
+            "%" => Operator::Modulo,
+            "<<" => Operator::ShiftLeft,
+            ">>" => Operator::ShiftRight,
             "||" => Operator::Or,
+            "&&" => Operator::And,
+            "|" => Operator::BitOr,
+            "&" => Operator::BitAnd,
+            "^" => Operator::BitXor,
             "==" => Operator::Equals,
             "!=" => Operator::NotEquals,
+            "<" => Operator::LessThan,
+            ">" => Operator::GreaterThan,
+            "<=" => Operator::LessOrEqual,
+            ">=" => Operator::GreaterOrEqual,
+            _ => panic!(),
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Modulo => "%",
+            Operator::ShiftLeft => "<<",
+            Operator::ShiftRight => ">>",
+            Operator::Or => "||",
+            Operator::And => "&&",
+            Operator::BitOr => "|",
+            Operator::BitAnd => "&",
+            Operator::BitXor => "^",
+            Operator::Equals => "==",
+            Operator::NotEquals => "!=",
+            Operator::LessThan => "<",
+            Operator::GreaterThan => ">",
+            Operator::LessOrEqual => "<=",
+            Operator::GreaterOrEqual => ">=",
+        }
+    }
+
+    // higher binds tighter; all prefix operators bind tighter than any of these
+    fn precedence(&self) -> usize {
+        match self {
+            Operator::Or => 1,
+            Operator::And => 2,
+            Operator::BitOr => 3,
+            Operator::BitXor => 4,
+            Operator::BitAnd => 5,
+            Operator::Equals | Operator::NotEquals => 6,
+            Operator::LessThan | Operator::GreaterThan
+            | Operator::LessOrEqual | Operator::GreaterOrEqual => 7,
+            Operator::ShiftLeft | Operator::ShiftRight => 8,
+            Operator::Add | Operator::Subtract => 9,
+            Operator::Multiply | Operator::Divide | Operator::Modulo => 10,
+        }
+    }
+
+    // operators whose result is a truth value, evaluated by branching
+    fn is_logical(&self) -> bool {
+        match self {
+            Operator::Or | Operator::And | Operator::Equals | Operator::NotEquals => true,
+            _ => self.relation().is_some(),
+        }
+    }
+
+    // the exact value, for constant folding; whether it fits is up to the type check.
+    // None leaves the operation to run time, e.g. dividing by zero.
+    fn evaluate(&self, a: i32, b: i32) -> Option<i32> {
+        let negative = a < 0 || b < 0;
+        match *self {
+            Operator::Add => a.checked_add(b),
+            Operator::Subtract => a.checked_sub(b),
+            Operator::Multiply => a.checked_mul(b),
+            Operator::Divide | Operator::Modulo | Operator::ShiftLeft | Operator::ShiftRight if negative => None,
+            Operator::Divide => a.checked_div(b),
+            Operator::Modulo => a.checked_rem(b),
+            Operator::ShiftLeft if b < 16 => a.checked_mul(1 << b),
+            Operator::ShiftRight if b < 16 => Some(a >> b),
+            Operator::ShiftLeft | Operator::ShiftRight => None,
+            Operator::Or => Some((a != 0 || b != 0) as i32),
+            Operator::And => Some((a != 0 && b != 0) as i32),
+            Operator::BitOr => Some(a | b),
+            Operator::BitAnd => Some(a & b),
+            Operator::BitXor => Some(a ^ b),
+            Operator::Equals => Some((a == b) as i32),
+            Operator::NotEquals => Some((a != b) as i32),
+            // whether a negative literal compares as negative depends on the operand type
+            _ if negative && self.relation().is_some() => None,
+            Operator::LessThan => Some((a < b) as i32),
+            Operator::GreaterThan => Some((a > b) as i32),
+            Operator::LessOrEqual => Some((a <= b) as i32),
+            Operator::GreaterOrEqual => Some((a >= b) as i32),
+        }
+    }
+
+    // operators without an instruction are calls into the runtime
+    fn runtime_function(&self) -> Option<&'static str> {
+        match self {
+            Operator::Divide => Some("__div8"),
+            Operator::Modulo => Some("__mod8"),
+            Operator::ShiftLeft => Some("__shl8"),
+            Operator::ShiftRight => Some("__shr8"),
+            _ => None,
+        }
+    }
+
+    // relational operators compile to "is one operand greater than the other", optionally negated:
+    // (left is the greater side, negate)
+    fn relation(&self) -> Option<(bool, bool)> {
+        match *self {
+            Operator::GreaterThan => Some((true, false)),
+            Operator::LessThan => Some((false, false)),
+            Operator::LessOrEqual => Some((true, true)),
+            Operator::GreaterOrEqual => Some((false, true)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Type {
+    U8,
+    I8,
+    U16,
+    I16,
+    // 0 or 1, stored as a u8
+    Bool,
+    // an address in RAM; there are no pointers to pointers
+    Pointer(&'static Type),
+}
+
+impl Type {
+    fn parse(pair: pest::iterators::Pair<Rule>) -> Type {
+        assert_eq!(Rule::type_name, pair.as_rule());
+        let name = pair.as_str();
+        match name.strip_prefix('*') {
+            Some(pointee) => Type::named(pointee).pointer_to().unwrap(),
+            None => Type::named(name),
+        }
+    }
+
+    fn named(name: &str) -> Type {
+        match name {
+            "u8" => Type::U8,
+            "i8" => Type::I8,
+            "u16" => Type::U16,
+            "i16" => Type::I16,
+            "bool" => Type::Bool,
             _ => panic!(),
         }
     }
+
+    // the type of the address of a value of this type
+    fn pointer_to(self) -> Option<Type> {
+        let pointee = match self {
+            Type::U8 => &Type::U8,
+            Type::I8 => &Type::I8,
+            Type::U16 => &Type::U16,
+            Type::I16 => &Type::I16,
+            Type::Bool => &Type::Bool,
+            Type::Pointer(_) => return None,
+        };
+        Some(Type::Pointer(pointee))
+    }
+
+    // stack slots (or bytes of memory) a value takes
+    fn size(&self) -> usize {
+        match self {
+            Type::U8 | Type::I8 | Type::Bool | Type::Pointer(_) => 1,
+            Type::U16 | Type::I16 => 2,
+        }
+    }
+
+    fn is_wide(&self) -> bool {
+        self.size() == 2
+    }
+
+    fn is_signed(&self) -> bool {
+        *self == Type::I8 || *self == Type::I16
+    }
+
+    // the values a literal of this type may have
+    fn range(&self) -> RangeInclusive<i32> {
+        match self {
+            Type::U8 => 0..=0xff,
+            Type::I8 => -0x80..=0x7f,
+            Type::U16 => 0..=0xffff,
+            Type::I16 => -0x8000..=0x7fff,
+            Type::Bool => 0..=1,
+            Type::Pointer(_) => 0..=0xff,
+        }
+    }
+
+    // the type a literal is emitted as when nothing gives it one
+    fn of_literal(n: i32) -> Type {
+        if Type::U8.range().contains(&n) {
+            Type::U8
+        } else if Type::I8.range().contains(&n) {
+            Type::I8
+        } else if n < 0 {
+            Type::I16
+        } else {
+            Type::U16
+        }
+    }
+
+    // what `n as self` evaluates to
+    fn convert(&self, n: i32) -> i32 {
+        match self {
+            Type::U8 | Type::Pointer(_) => n as u8 as i32,
+            Type::I8 => n as i8 as i32,
+            Type::U16 => n as u16 as i32,
+            Type::I16 => n as i16 as i32,
+            Type::Bool => (n != 0) as i32,
+        }
+    }
+
+    // conversions that keep every value can be left implicit; anything else needs an `as`
+    fn converts_to(self, to: Type) -> bool {
+        self == to || matches!((self, to),
+            (Type::Bool, Type::U8 | Type::I8 | Type::U16 | Type::I16) | (Type::U8, Type::U16) | (Type::U8, Type::I16) | (Type::I8, Type::I16))
+    }
+
+    // the type both operands of a binary operation are converted to
+    fn unify(self, other: Type) -> Option<Type> {
+        if self.converts_to(other) {
+            Some(other)
+        } else if other.converts_to(self) {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    // arithmetic on truth values is done in u8
+    fn arithmetic(self) -> Type {
+        if self == Type::Bool { Type::U8 } else { self }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::U8 => "u8",
+            Type::I8 => "i8",
+            Type::U16 => "u16",
+            Type::I16 => "i16",
+            Type::Bool => "bool",
+            Type::Pointer(pointee) => return write!(f, "*{}", pointee),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// the types visible inside a function: its variables and every function's signature
+struct Scope {
+    variables: BTreeMap<String, Type>,
+    functions: BTreeMap<String, (Vec<Type>, Type)>,
+}
+
+impl Scope {
+    fn variable(&self, name: &str) -> Type {
+        self.variables.get(name).cloned().unwrap_or(Type::U8)
+    }
+}
+
+// n -> ACC, or pushed if push
+fn load_constant(n: u8, push: bool) -> Vec<Instruction> {
+    let sign_extended = ((n as i8) << 4) >> 4;
+    let needs_load_hi = sign_extended != (n as i8);
+
+    if needs_load_hi {
+        vec![
+            Instruction::WithoutPush(PushableInstruction::LoadLo(Target::Absolute(n & 0xF))),
+            Instruction::with_push(push, PushableInstruction::LoadHi(Target::Absolute((n>>4) & 0xF))),
+        ]
+    } else {
+        vec![Instruction::with_push(push, PushableInstruction::LoadLo(Target::Absolute(n & 0xF)))]
+    }
 }
 
 struct FunctionContext {
@@ -46,6 +343,12 @@ struct FunctionContext {
     pub lines: Vec<Line>,
     pub additional_offset: usize,
     pub block_counter: usize,
+    pub function_name: String,
+    pub bounds_check: bool,
+    pub scope: Scope,
+    // keep FRAME_POINTER up to date across calls
+    pub frame_pointer: bool,
+    pub uses_frame_pointer: bool,
 }
 
 impl FunctionContext {
@@ -55,14 +358,206 @@ impl FunctionContext {
     }
 
     fn add_macro(&mut self, s: String) {
-        let line = Line::parse(s);
+        let line = Line::parse(s).expect("invalid macro");
         self.lines.push(line);
     }
 
+    // sp+0 c, sp+1 y, sp+2 x -> sp+0 the carry out of x + y + c (0 or 1), sp+1 the sum;
+    // mark3 can't read the carry an ADD sets, so a runtime routine works it out
+    fn add_with_carry(&mut self) {
+        self.add_macro("call :__adc8".to_string());
+    }
+
+    // a label unique within the function, e.g. :main_EQ_FALSE_3
+    fn new_label(&mut self, kind: &str) -> String {
+        let label = format!(":{}_{}_{}", self.function_name, kind, self.block_counter);
+        self.block_counter += 1;
+        label
+    }
+
+    // slots beyond a StackOffset are reached through ADDR like a global, so the code stays
+    // the same size however deep the slot is
+    fn load_stack(&mut self, offset: usize, push: bool) {
+        if offset < 8 {
+            self.add_inst(Instruction::with_push(push, PushableInstruction::LoadFromStack(StackOffset::new(offset as u8))));
+        } else {
+            self.stack_address(offset, false);
+            self.add_inst(Instruction::StoreAddr);
+            self.add_inst(Instruction::with_push(push, PushableInstruction::LoadMem));
+        }
+    }
+
+    // ACC -> slot
+    fn store_stack(&mut self, offset: usize) {
+        if offset < 8 {
+            self.add_inst(Instruction::StoreToStack(StackOffset::new(offset as u8)));
+        } else {
+            // ADDR can only be written from ACC, so park the value meanwhile
+            self.alloc(1);
+            self.add_inst(Instruction::StoreToStack(StackOffset::top()));
+            self.stack_address(offset, false);
+            self.add_inst(Instruction::StoreAddr);
+            self.add_inst(Instruction::PopDiscard(StackOffset::top()));
+            self.add_inst(Instruction::StoreMem);
+        }
+    }
+
+    // SP + offset -> ACC, or pushed if push. SP can't be read, so the address is found from
+    // FRAME_POINTER, which holds the address of the return address slot
+    fn stack_address(&mut self, offset: usize, push: bool) {
+        self.uses_frame_pointer = true;
+        let return_address = match self.find_local(RETURN_ADDRESS) {
+            LocalStorage::Stack(return_address) => return_address,
+            _ => unreachable!(),
+        };
+        self.load_constant(FRAME_POINTER, false);
+        self.add_inst(Instruction::StoreAddr);
+        self.add_inst(Instruction::WithPush(PushableInstruction::LoadMem));
+        self.load_constant(offset.wrapping_sub(return_address) as u8, false);
+        self.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::top())));
+        if push {
+            self.add_inst(Instruction::StoreToStack(StackOffset::top()));
+        } else {
+            self.add_inst(Instruction::Discard(StackOffset::new(1)));
+        }
+    }
+
+    // FRAME_POINTER += delta
+    fn move_frame_pointer(&mut self, delta: u8) {
+        self.load_constant(FRAME_POINTER, false);
+        self.add_inst(Instruction::StoreAddr);
+        self.add_inst(Instruction::WithPush(PushableInstruction::LoadMem));
+        self.load_constant(delta, false);
+        self.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::top())));
+        self.add_inst(Instruction::Discard(StackOffset::new(1)));
+        self.add_inst(Instruction::StoreMem);
+    }
+
+    fn discard(&mut self, mut count: usize) {
+        while count > 0 {
+            let n = std::cmp::min(count, 7);
+            self.add_inst(Instruction::Discard(StackOffset::new(n as u8)));
+            count -= n;
+        }
+    }
+
+    fn alloc(&mut self, mut count: usize) {
+        while count > 0 {
+            let n = std::cmp::min(count, 7);
+            self.add_inst(Instruction::Alloc(StackOffset::new(n as u8)));
+            count -= n;
+        }
+    }
+
+    fn load_constant(&mut self, n: u8, push: bool) {
+        for i in load_constant(n, push) {
+            self.add_inst(i);
+        }
+    }
+
+    // variable -> ACC, or pushed if push
+    fn load_local(&mut self, local: &str, push: bool) {
+        self.load_local_byte(local, 0, push)
+    }
+
+    // byte 1 is the high byte of a 16-bit variable
+    fn load_local_byte(&mut self, local: &str, byte: usize, push: bool) {
+        match self.find_local(local) {
+            LocalStorage::Register(_r) => {
+                // ctxt.add_inst(Instruction::LoadReg(r));
+                unimplemented!();
+            },
+            LocalStorage::Stack(offset) => {
+                self.load_stack(offset + byte, push);
+            },
+            LocalStorage::Global(address) => {
+                self.load_constant(address + byte as u8, false);
+                self.add_inst(Instruction::StoreAddr);
+                self.add_inst(Instruction::with_push(push, PushableInstruction::LoadMem));
+            },
+            LocalStorage::GlobalArray(..) | LocalStorage::StackArray(..) => {
+                return self.load_array_address(local, push);
+            },
+        }
+
+        if push {
+            self.additional_offset += 1;
+        }
+    }
+
+    // ACC -> variable
+    fn store_local(&mut self, local: &str) {
+        self.store_local_byte(local, 0)
+    }
+
+    fn store_local_byte(&mut self, local: &str, byte: usize) {
+        match self.find_local(local) {
+            LocalStorage::Register(_r) => {
+                unimplemented!();
+            },
+            LocalStorage::Stack(offset) => {
+                self.store_stack(offset + byte);
+            },
+            LocalStorage::Global(address) => {
+                // ADDR can only be written from ACC, so park the value meanwhile
+                self.alloc(1);
+                self.add_inst(Instruction::StoreToStack(StackOffset::top()));
+                self.load_constant(address + byte as u8, false);
+                self.add_inst(Instruction::StoreAddr);
+                self.add_inst(Instruction::PopDiscard(StackOffset::top()));
+                self.add_inst(Instruction::StoreMem);
+            },
+            LocalStorage::GlobalArray(..) | LocalStorage::StackArray(..) => panic!("cannot assign to array {}", local),
+        }
+    }
+
+    // pushes a variable as 16 bits, high byte first so the low byte ends up on top
+    fn load_wide(&mut self, local: &str) {
+        if self.scope.variable(local).is_wide() {
+            self.load_local_byte(local, 1, true);
+        } else {
+            self.load_constant(0, true);
+            self.additional_offset += 1;
+        }
+        self.load_local_byte(local, 0, true);
+    }
+
+    // pops a 16-bit value into a variable; a u8 variable keeps the low byte
+    fn store_wide(&mut self, local: &str) {
+        self.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::top())));
+        self.store_local_byte(local, 0);
+        if self.scope.variable(local).is_wide() {
+            self.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::new(1))));
+            self.store_local_byte(local, 1);
+        }
+        self.add_inst(Instruction::Discard(StackOffset::new(2)));
+        self.additional_offset -= 2;
+    }
+
+    // address of the array's first element -> ACC, or pushed if push
+    fn load_array_address(&mut self, array: &str, push: bool) {
+        match self.find_local(array) {
+            LocalStorage::GlobalArray(address, _) => self.load_constant(address, push),
+            LocalStorage::StackArray(offset, _) => self.stack_address(offset, push),
+            _ => panic!("{} is not an array", array),
+        }
+
+        if push {
+            self.additional_offset += 1;
+        }
+    }
+
+    fn array_length(&mut self, array: &str) -> usize {
+        match self.find_local(array) {
+            LocalStorage::GlobalArray(_, length) | LocalStorage::StackArray(_, length) => length,
+            _ => panic!("{} is not an array", array),
+        }
+    }
+
     fn find_local(&mut self, local: &str) -> LocalStorage {
         let local = self.stack
             .get(local)
-            .expect(&format!("could not find {}", local));
+            .unwrap_or_else(|| panic!("could not find {}", local));
         match local {
             LocalStorage::Stack(offset) => {
                 LocalStorage::Stack(*offset + self.additional_offset)
@@ -70,100 +565,610 @@ impl FunctionContext {
             LocalStorage::Register(r) => {
                 self.regs_touched.insert(*r);
                 LocalStorage::Register(*r)
-            }
+            },
+            LocalStorage::StackArray(offset, length) => {
+                LocalStorage::StackArray(*offset + self.additional_offset, *length)
+            },
+            LocalStorage::Global(_) | LocalStorage::GlobalArray(..) => *local,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum UnaryOperator {
+    Negate,
+    Complement,
+    Not,
+}
+
+impl UnaryOperator {
+    fn parse(pair: pest::iterators::Pair<Rule>) -> UnaryOperator {
+        match pair.as_str() {
+            "-" => UnaryOperator::Negate,
+            "~" => UnaryOperator::Complement,
+            "!" => UnaryOperator::Not,
+            _ => panic!(),
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOperator::Negate => "-",
+            UnaryOperator::Complement => "~",
+            UnaryOperator::Not => "!",
+        }
+    }
+
+    // the complement depends on how wide the value is, so needs the width it is taken at
+    fn evaluate(&self, a: i32, width: Option<Type>) -> Option<i32> {
+        match self {
+            UnaryOperator::Negate => a.checked_neg(),
+            UnaryOperator::Complement => match width {
+                Some(ty) if ty.range().contains(&a) => Some(ty.convert(!a)),
+                _ => None,
+            },
+            UnaryOperator::Not => Some((a == 0) as i32),
         }
     }
 }
 
+// operation spans are only used for diagnostics
+#[allow(dead_code)]
 #[derive(Debug)]
 enum Expression {
-    Ident(String),
-    Number(i32),
-    Operation(Operator, Box<Expression>, Box<Expression>)
+    Ident(String, Span),
+    Number(i32, Span),
+    Operation(Operator, Box<Expression>, Box<Expression>, Span),
+    Unary(UnaryOperator, Box<Expression>, Span),
+    Call(String, Vec<Expression>, Span),
+    Index(String, Box<Expression>, Span),
+    Cast(Box<Expression>, Type, Span),
+    AddressOf(String, Span),
+    Deref(Box<Expression>, Span),
 }
 
 impl Expression {
-    fn parse(pair: pest::iterators::Pair<Rule>) -> Expression {
+    fn parse(pair: pest::iterators::Pair<Rule>) -> Result<Expression, Diagnostic> {
         assert_eq!(Rule::expression, pair.as_rule());
-        let pair = pair.into_inner().next().unwrap();
-        match pair.as_rule() {
+        let mut pairs = pair.into_inner().peekable();
+        let (expression, _) = Expression::climb(&mut pairs, 0)?;
+        Ok(expression)
+    }
+
+    // precedence climbing over the flat operand/operator list; binary operators are left associative
+    fn climb(pairs: &mut Peekable<Pairs<Rule>>, min_precedence: usize) -> Result<(Expression, Span), Diagnostic> {
+        let (mut left, mut span) = Expression::parse_operand(pairs)?;
+
+        // `as` binds tighter than binary operators but looser than prefix ones
+        while let Some(cast) = pairs.next_if(|p| p.as_rule() == Rule::cast) {
+            span = Span { start: span.start, end: cast.as_span().end() };
+            left = Expression::Cast(Box::new(left), Type::parse(cast.into_inner().next().unwrap()), span);
+        }
+
+        while let Some(op) = pairs.peek().map(|p| Operator::parse(p.clone())) {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            pairs.next();
+            let (right, right_span) = Expression::climb(pairs, op.precedence() + 1)?;
+            span = Span { start: span.start, end: right_span.end };
+            left = Expression::Operation(op, Box::new(left), Box::new(right), span);
+        }
+        Ok((left, span))
+    }
+
+    // the console ports are bytes in the I/O page
+    fn port(address: u8, span: Span) -> Expression {
+        Expression::Cast(Box::new(Expression::Number(address as i32, span)), Type::Pointer(&Type::U8), span)
+    }
+
+    fn parse_call(pair: pest::iterators::Pair<Rule>) -> Result<Expression, Diagnostic> {
+        assert_eq!(Rule::call_expression, pair.as_rule());
+        let mut pairs = pair.into_inner();
+        let function = pairs.next().unwrap();
+        let span = Span::of(&function);
+        let function = function.as_str().to_owned();
+
+        let mut parameters = Vec::new();
+        for arg in pairs {
+            parameters.push(Expression::parse(arg)?);
+        }
+
+        Ok(Expression::Call(function, parameters, span))
+    }
+
+    fn parse_index(pair: pest::iterators::Pair<Rule>) -> Result<Expression, Diagnostic> {
+        assert_eq!(Rule::index_expression, pair.as_rule());
+        let mut pairs = pair.into_inner();
+        let array = pairs.next().unwrap();
+        let span = Span::of(&array);
+        let index = Expression::parse(pairs.next().unwrap())?;
+        Ok(Expression::Index(array.as_str().to_owned(), Box::new(index), span))
+    }
+
+    // prefix operators followed by a number, identifier or parenthesised expression
+    fn parse_operand(pairs: &mut Peekable<Pairs<Rule>>) -> Result<(Expression, Span), Diagnostic> {
+        let pair = pairs.next().unwrap();
+        let span = Span::of(&pair);
+        Ok(match pair.as_rule() {
             Rule::number => {
-                let mut n = 0;
-                let mut digits = pair.into_inner();
-                while let Some(digit) = digits.next() {
-                    let digit = i32::from_str(digit.as_str()).expect("Couldn't parse integer.");
-                    n *= 10;
-                    n += digit;
-                }
-                Expression::Number(n)
+                let n = i32::from_str(pair.as_str())
+                    .map_err(|_| Diagnostic::new(span, "number is too large"))?;
+                (Expression::Number(n, span), span)
             },
             Rule::ident => {
                 let mut label = String::new();
-                let mut chars = pair.into_inner();
-                while let Some(c) = chars.next() {
+                for c in pair.into_inner() {
                     label += c.as_str();
                 }
-                Expression::Ident(label)
+                (Expression::Ident(label, span), span)
             },
-            Rule::operator_expression => {
-                let mut pairs = pair.into_inner();
-                let left = Expression::parse(pairs.next().unwrap());
-                let op = Operator::parse(pairs.next().unwrap());
-                let right = Expression::parse(pairs.next().unwrap());
-                Expression::Operation(op, Box::new(left), Box::new(right))
+            Rule::expression => (Expression::parse(pair)?, span),
+            Rule::call_expression => (Expression::parse_call(pair)?, span),
+            Rule::index_expression => (Expression::parse_index(pair)?, span),
+            Rule::address_of => {
+                let name = pair.into_inner().next().unwrap();
+                (Expression::AddressOf(name.as_str().to_owned(), span), span)
+            },
+            Rule::getc => (Expression::Deref(Box::new(Expression::port(CONSOLE_IN, span)), span), span),
+            Rule::deref => {
+                let (operand, operand_span) = Expression::parse_operand(pairs)?;
+                let span = Span { start: span.start, end: operand_span.end };
+                (Expression::Deref(Box::new(operand), span), span)
+            },
+            Rule::negate | Rule::complement | Rule::not => {
+                let (operand, operand_span) = Expression::parse_operand(pairs)?;
+                let span = Span { start: span.start, end: operand_span.end };
+                let expression = match (UnaryOperator::parse(pair), operand) {
+                    (UnaryOperator::Negate, Expression::Number(n, _)) => Expression::Number(-n, span),
+                    (op, operand) => Expression::Unary(op, Box::new(operand), span),
+                };
+                (expression, span)
             },
             _ => unimplemented!()
+        })
+    }
+
+    // replaces constants and operations on literals with their value. width is what a
+    // complement is taken at when the context gives one; otherwise emit folds it instead.
+    fn fold(&mut self, constants: &BTreeMap<String, i32>, width: Option<Type>) {
+        let value = match self {
+            Expression::Number(..) => None,
+            Expression::Ident(name, _) => constants.get(name).cloned(),
+            Expression::Operation(op, left, right, _) => {
+                left.fold(constants, width);
+                right.fold(constants, width);
+                match (&**left, &**right) {
+                    (Expression::Number(a, _), Expression::Number(b, _)) => op.evaluate(*a, *b),
+                    _ => None,
+                }
+            },
+            Expression::Unary(op, operand, _) => {
+                operand.fold(constants, width);
+                match **operand {
+                    Expression::Number(a, _) => op.evaluate(a, width),
+                    _ => None,
+                }
+            },
+            Expression::Cast(operand, ty, _) => {
+                operand.fold(constants, Some(*ty));
+                match **operand {
+                    // a literal address keeps the pointer type its cast gives it
+                    Expression::Number(_, _) if matches!(ty, Type::Pointer(_)) => None,
+                    Expression::Number(a, _) => Some(ty.convert(a)),
+                    _ => None,
+                }
+            },
+            Expression::Call(_, parameters, _) => {
+                for p in parameters {
+                    p.fold(constants, None);
+                }
+                None
+            },
+            Expression::Index(_, index, _) | Expression::Deref(index, _) => {
+                index.fold(constants, None);
+                None
+            },
+            Expression::AddressOf(..) => None,
+        };
+
+        if let Some(value) = value {
+            *self = Expression::Number(value, self.span());
+        }
+    }
+
+    // the value of a literal, or a complement or negation of one, which fold leaves where the
+    // width is unknown; truncating it to the width gives the same bits as computing at it
+    fn literal(&self) -> Option<i32> {
+        match self {
+            Expression::Number(n, _) => Some(*n),
+            Expression::Unary(UnaryOperator::Complement, operand, _) => operand.literal().map(|n| !n),
+            Expression::Unary(UnaryOperator::Negate, operand, _) => operand.literal().and_then(i32::checked_neg),
+            _ => None,
+        }
+    }
+
+    fn span(&self) -> Span {
+        match self {
+            Expression::Ident(_, span) | Expression::Number(_, span) | Expression::Operation(_, _, _, span)
+            | Expression::Unary(_, _, span) | Expression::Call(_, _, span) | Expression::Index(_, _, span)
+            | Expression::Cast(_, _, span) | Expression::AddressOf(_, span) | Expression::Deref(_, span) => *span,
+        }
+    }
+
+    fn check(&self, f: &Function, program: &Program, diagnostics: &mut Vec<Diagnostic>) {
+        match self {
+            Expression::Number(..) => {},
+            Expression::Ident(name, span) => {
+                if f.is_array(name, program).is_none() {
+                    diagnostics.push(Diagnostic::new(*span, format!("undefined variable '{}'", name)));
+                }
+            },
+            Expression::Index(array, index, span) => {
+                check_array(array, *span, f, program, diagnostics);
+                index.check(f, program, diagnostics);
+            },
+            Expression::Operation(_, left, right, _) => {
+                left.check(f, program, diagnostics);
+                right.check(f, program, diagnostics);
+            },
+            Expression::Unary(_, operand, _) | Expression::Cast(operand, _, _) | Expression::Deref(operand, _) => {
+                operand.check(f, program, diagnostics);
+            },
+            Expression::AddressOf(name, span) => match f.is_array(name, program) {
+                None => diagnostics.push(Diagnostic::new(*span, format!("undefined variable '{}'", name))),
+                Some(true) => {},
+                Some(false) if f.is_global(name, program) => {},
+                Some(false) => diagnostics.push(Diagnostic::new(*span, format!("'{}' has no address; only globals and arrays do", name))),
+            },
+            Expression::Call(function, parameters, span) => {
+                match program.functions.get(function) {
+                    None => diagnostics.push(Diagnostic::new(*span, format!("unknown function '{}'", function))),
+                    Some(callee) if callee.args.len() != parameters.len() => {
+                        diagnostics.push(Diagnostic::new(*span, format!("{} takes {} argument(s) but {} were given",
+                            function, callee.args.len(), parameters.len())));
+                    },
+                    Some(_) => {},
+                }
+                for p in parameters {
+                    p.check(f, program, diagnostics);
+                }
+            },
+        }
+    }
+
+    // the type of the expression, or None for a literal, which takes its type from where it is used
+    fn infer(&self, scope: &Scope, diagnostics: &mut Vec<Diagnostic>) -> Option<Type> {
+        match self {
+            Expression::Number(..) => None,
+            Expression::Ident(name, _) => Some(scope.variable(name)),
+            Expression::Index(_, index, _) => {
+                index.expect(Type::U8, scope, diagnostics);
+                Some(Type::U8)
+            },
+            Expression::Call(function, parameters, _) => {
+                let (types, returns) = &scope.functions[function];
+                for (p, ty) in parameters.iter().zip(types) {
+                    p.expect(*ty, scope, diagnostics);
+                }
+                Some(*returns)
+            },
+            Expression::Cast(operand, ty, _) => {
+                // any integer converts explicitly, literals included
+                operand.infer(scope, diagnostics);
+                Some(*ty)
+            },
+            Expression::AddressOf(name, span) => {
+                let ty = scope.variable(name);
+                match ty.pointer_to() {
+                    Some(pointer) => Some(pointer),
+                    None => {
+                        diagnostics.push(Diagnostic::new(*span, format!("cannot take the address of '{}', which is {}", name, ty)));
+                        Some(ty)
+                    },
+                }
+            },
+            Expression::Deref(pointer, _) => Some(pointer.pointee(scope, diagnostics)),
+            Expression::Unary(UnaryOperator::Not, operand, _) => {
+                operand.infer(scope, diagnostics);
+                Some(Type::Bool)
+            },
+            Expression::Unary(op, operand, span) => {
+                let ty = operand.infer(scope, diagnostics).map(Type::arithmetic);
+                if let Some(Type::Pointer(_)) = ty {
+                    diagnostics.push(Diagnostic::new(*span, format!("'{}' is not supported for pointers", op.symbol())));
+                }
+                ty
+            },
+            Expression::Operation(Operator::Or, left, right, _)
+            | Expression::Operation(Operator::And, left, right, _) => {
+                left.infer(scope, diagnostics);
+                right.infer(scope, diagnostics);
+                Some(Type::Bool)
+            },
+            Expression::Operation(op, left, right, span) => {
+                // inferred once: inferring it again for each case would be exponential in the depth
+                let left_ty = left.infer(scope, diagnostics);
+                let ty = match op {
+                    // pointer arithmetic counts in elements
+                    Operator::Add | Operator::Subtract if matches!(left_ty, Some(Type::Pointer(_))) => {
+                        right.expect(Type::U8, scope, diagnostics);
+                        return left_ty;
+                    },
+                    // the shift count is separate from the value shifted
+                    Operator::ShiftLeft | Operator::ShiftRight => {
+                        right.expect(Type::U8, scope, diagnostics);
+                        left_ty
+                    },
+                    _ => {
+                        let arithmetic = matches!(op, Operator::Add | Operator::Subtract
+                            | Operator::Multiply | Operator::Divide | Operator::Modulo);
+                        Expression::unify_inferred(left, left_ty, right, arithmetic, scope, diagnostics)
+                    },
+                };
+
+                if let Some(Type::Pointer(_)) = ty {
+                    if !op.is_logical() {
+                        diagnostics.push(Diagnostic::new(*span, format!("'{}' is not supported for pointers", op.symbol())));
+                    }
+                } else if let (Some(ty), Some(_)) = (ty, op.runtime_function()) {
+                    if ty.is_wide() {
+                        diagnostics.push(Diagnostic::new(*span, format!("'{}' is not supported for 16-bit values", op.symbol())));
+                    } else if ty.is_signed() && *op != Operator::ShiftLeft {
+                        diagnostics.push(Diagnostic::new(*span, format!("'{}' is not supported for signed values", op.symbol())));
+                    }
+                }
+
+                match op {
+                    Operator::BitAnd | Operator::BitOr | Operator::BitXor => ty,
+                    _ if op.is_logical() => Some(Type::Bool),
+                    _ => ty.map(Type::arithmetic),
+                }
+            },
+        }
+    }
+
+    // the type both operands are converted to; a literal takes the type of the other side.
+    // arithmetic operands that are truth values count as u8.
+    fn unify(left: &Expression, right: &Expression, arithmetic: bool, scope: &Scope, diagnostics: &mut Vec<Diagnostic>) -> Option<Type> {
+        let left_ty = left.infer(scope, diagnostics);
+        Expression::unify_inferred(left, left_ty, right, arithmetic, scope, diagnostics)
+    }
+
+    // as unify, for a left operand whose type has already been inferred
+    fn unify_inferred(left: &Expression, left_ty: Option<Type>, right: &Expression, arithmetic: bool,
+        scope: &Scope, diagnostics: &mut Vec<Diagnostic>) -> Option<Type> {
+        let promote = |ty: Option<Type>| if arithmetic { ty.map(Type::arithmetic) } else { ty };
+        match (promote(left_ty), promote(right.infer(scope, diagnostics))) {
+            (Some(l), Some(r)) => match l.unify(r) {
+                Some(ty) => Some(ty),
+                None => {
+                    let span = Span { start: left.span().start, end: right.span().end };
+                    diagnostics.push(Diagnostic::new(span, format!("mismatched types {} and {}", l, r)));
+                    Some(l)
+                },
+            },
+            (Some(ty), None) => {
+                right.check_literal(ty, diagnostics);
+                Some(ty)
+            },
+            (None, Some(ty)) => {
+                left.check_literal(ty, diagnostics);
+                Some(ty)
+            },
+            (None, None) => None,
+        }
+    }
+
+    // the type a pointer points to; anything else is reported and read as a u8
+    fn pointee(&self, scope: &Scope, diagnostics: &mut Vec<Diagnostic>) -> Type {
+        match self.infer(scope, diagnostics) {
+            Some(Type::Pointer(pointee)) => *pointee,
+            found => {
+                let found = found.unwrap_or(Type::U8);
+                diagnostics.push(Diagnostic::new(self.span(), format!("expected a pointer, found {}", found)));
+                Type::U8
+            },
+        }
+    }
+
+    // LOAD and STORE take either a pointer or a plain u8 address
+    fn expect_address(&self, scope: &Scope, diagnostics: &mut Vec<Diagnostic>) {
+        match self.infer(scope, diagnostics) {
+            Some(Type::Pointer(_)) => {},
+            None => self.check_literal(Type::U8, diagnostics),
+            Some(found) if found.converts_to(Type::U8) => {},
+            Some(found) => diagnostics.push(Diagnostic::new(self.span(), format!("expected an address, found {}", found))),
+        }
+    }
+
+    // reports an error unless the expression can be used where a value of type ty is wanted
+    fn expect(&self, ty: Type, scope: &Scope, diagnostics: &mut Vec<Diagnostic>) {
+        match self.infer(scope, diagnostics) {
+            None => self.check_literal(ty, diagnostics),
+            Some(found) if found.converts_to(ty) => {},
+            Some(found) => diagnostics.push(Diagnostic::new(self.span(), format!("expected {}, found {}", ty, found))),
+        }
+    }
+
+    fn check_literal(&self, ty: Type, diagnostics: &mut Vec<Diagnostic>) {
+        match self {
+            Expression::Number(n, span) if !ty.range().contains(n) => {
+                diagnostics.push(Diagnostic::new(*span, format!("literal {} is out of range for {}", n, ty)));
+            },
+            // ~5 takes the type it is used as, just as 5 does
+            Expression::Unary(UnaryOperator::Complement, operand, _)
+            | Expression::Unary(UnaryOperator::Negate, operand, _) => operand.check_literal(ty, diagnostics),
+            _ => {},
         }
     }
 
+    // the type emitted for both operands of a binary operation
+    fn common_type(left: &Expression, right: &Expression, scope: &Scope) -> Type {
+        Expression::unify(left, right, false, scope, &mut Vec::new()).unwrap_or(Type::U8)
+    }
+
+    // the type the expression is emitted as; only called once the types have been checked
+    fn ty(&self, scope: &Scope) -> Type {
+        match self.infer(scope, &mut Vec::new()) {
+            Some(ty) => ty,
+            None => match self {
+                Expression::Number(n, _) => Type::of_literal(*n),
+                _ => Type::U8,
+            },
+        }
+    }
+
+    #[allow(dead_code)]
     fn is_tail(&self) -> bool {
         match self {
-            Expression::Ident(_) => true,
-            Expression::Number(_) => true,
-            Expression::Operation(_,_,_) => false,
+            Expression::Ident(_,_) => true,
+            Expression::Number(_,_) => true,
+            Expression::Operation(_,_,_,_) => false,
+            Expression::Unary(_,_,_) => false,
+            Expression::Call(_,_,_) => false,
+            Expression::Index(_,_,_) => false,
+            Expression::Cast(_,_,_) => false,
+            Expression::AddressOf(_,_) => true,
+            Expression::Deref(_,_) => false,
         }
     }
 
     // if target_stack, output is in top of stack; else, in ACC
-    fn emit(&self, ctxt: &mut FunctionContext, target_stack: bool) -> () {
+    fn emit(&self, ctxt: &mut FunctionContext, target_stack: bool) {
         ctxt.lines.push(Line::Comment(format!("Evaluating expression: {:?} additional_offset:{}", &self, ctxt.additional_offset)));
 
-        match self {
-            Expression::Number(n) => {
-                let n = *n as u8;
-                let sign_extended = ((n as i8) << 4) >> 4;
-                let needs_load_hi = sign_extended != (n as i8);
+        let ty = self.ty(&ctxt.scope);
+
+        // only the low byte of a 16-bit value is wanted; literals are simply truncated
+        if self.literal().is_none() && ty.is_wide() {
+            self.emit_wide(ctxt);
+            ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::top())));
+            if target_stack {
+                ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(1)));
+                ctxt.discard(1);
+                ctxt.additional_offset -= 1;
+            } else {
+                ctxt.discard(2);
+                ctxt.additional_offset -= 2;
+            }
+            ctxt.lines.push(Line::Comment(format!("Evaluated expression: {:?} additional_offset:{}", &self, ctxt.additional_offset)));
+            return;
+        }
 
-                if needs_load_hi {
-                    ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadLo(Target::Absolute(n & 0xF))));
-                    ctxt.add_inst(Instruction::with_push(target_stack, PushableInstruction::LoadHi(Target::Absolute((n>>4) & 0xF))));
+        if let Some(n) = self.literal() {
+            ctxt.load_constant(n as u8, target_stack);
+            if target_stack {
+                ctxt.additional_offset += 1;
+            }
+            ctxt.lines.push(Line::Comment(format!("Evaluated expression: {:?} additional_offset:{}", &self, ctxt.additional_offset)));
+            return;
+        }
 
-                } else {
-                    ctxt.add_inst(Instruction::with_push(target_stack, PushableInstruction::LoadLo(Target::Absolute(n & 0xF))));
+        match self {
+            Expression::Number(..) => unreachable!(),
+            Expression::Ident(n, _) => ctxt.load_local(n, target_stack),
+            Expression::Operation(op @ Operator::LessThan, left, right, _)
+            | Expression::Operation(op @ Operator::GreaterThan, left, right, _) => {
+                let (left_greater, _) = op.relation().unwrap();
+                Expression::emit_greater(ctxt, left, right, left_greater);
+                if target_stack {
+                    ctxt.alloc(1);
+                    ctxt.add_inst(Instruction::StoreToStack(StackOffset::top()));
+                    ctxt.additional_offset += 1;
                 }
-
+            },
+            Expression::Call(function, parameters, _) => {
+                let (types, returns) = ctxt.scope.functions[function].clone();
+                let parameters : Vec<(&Expression, Type)> = parameters.iter().zip(types).collect();
+                Expression::emit_call(ctxt, function, &parameters, returns, target_stack);
+            },
+            Expression::Index(array, index, _) => {
+                Expression::emit_element_address(ctxt, array, index);
+                ctxt.add_inst(Instruction::StoreAddr);
+                ctxt.add_inst(Instruction::with_push(target_stack, PushableInstruction::LoadMem));
                 if target_stack {
                     ctxt.additional_offset += 1;
                 }
             },
-            Expression::Ident(n) => {
-                let local = ctxt.find_local(n);
-                match local {
-                    LocalStorage::Register(r) => {
-                        // ctxt.add_inst(Instruction::LoadReg(r));
-                        unimplemented!();
-                    },
-                    LocalStorage::Stack(offset) => {
-                        ctxt.add_inst(Instruction::with_push(target_stack, 
-                            PushableInstruction::LoadFromStack(StackOffset::new(offset as u8))));
-
-                        if target_stack {
-                            ctxt.additional_offset += 1;
-                        }
+            Expression::AddressOf(name, _) => match ctxt.find_local(name) {
+                LocalStorage::Global(address) => {
+                    ctxt.load_constant(address, target_stack);
+                    if target_stack {
+                        ctxt.additional_offset += 1;
                     }
+                },
+                _ => ctxt.load_array_address(name, target_stack),
+            },
+            Expression::Deref(pointer, _) => {
+                pointer.emit(ctxt, false);
+                ctxt.add_inst(Instruction::StoreAddr);
+                ctxt.add_inst(Instruction::with_push(target_stack, PushableInstruction::LoadMem));
+                if target_stack {
+                    ctxt.additional_offset += 1;
+                }
+            },
+            // pointer arithmetic has the type of the pointer
+            Expression::Operation(op @ Operator::Add, left, right, _)
+            | Expression::Operation(op @ Operator::Subtract, left, right, _)
+                if matches!(ty, Type::Pointer(pointee) if pointee.is_wide()) => {
+                left.emit(ctxt, true);
+                right.emit(ctxt, true);
+
+                // sp+0 right
+                // sp+1 left
+                // 16-bit elements are two bytes apart
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::top())));
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::top())));
+                if *op == Operator::Subtract {
+                    ctxt.add_inst(Instruction::StoreToStack(StackOffset::top()));
+                    ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Not(StackOffset::top())));
+                    ctxt.add_inst(Instruction::StoreToStack(StackOffset::top()));
+                    ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadLo(Target::Absolute(1))));
+                    ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::top())));
+                }
+                ctxt.add_inst(Instruction::Discard(StackOffset::new(1)));
+                ctxt.additional_offset -= 1;
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::top())));
+
+                if target_stack {
+                    ctxt.add_inst(Instruction::StoreToStack(StackOffset::top()));
+                } else {
+                    ctxt.add_inst(Instruction::Discard(StackOffset::new(1)));
+                    ctxt.additional_offset -= 1;
+                }
+            },
+            Expression::Cast(_, Type::Bool, _) => self.emit_truth(ctxt, target_stack),
+            // between 8-bit types the bits stay the same
+            Expression::Cast(operand, _, _) => operand.emit(ctxt, target_stack),
+            Expression::Operation(op, left, right, _) if op.runtime_function().is_some() => {
+                let parameters = [(&**left, Type::U8), (&**right, Type::U8)];
+                Expression::emit_call(ctxt, op.runtime_function().unwrap(), &parameters, Type::U8, target_stack);
+            },
+            Expression::Operation(op, _, _, _) if op.is_logical() => {
+                self.emit_truth(ctxt, target_stack);
+            },
+            Expression::Unary(UnaryOperator::Not, _, _) => {
+                self.emit_truth(ctxt, target_stack);
+            },
+            Expression::Unary(op @ UnaryOperator::Complement, operand, _)
+            | Expression::Unary(op @ UnaryOperator::Negate, operand, _) => {
+                operand.emit(ctxt, true);
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Not(StackOffset::top())));
+                if *op == UnaryOperator::Negate {
+                    // -x == ~x + 1
+                    ctxt.add_inst(Instruction::StoreToStack(StackOffset::top()));
+                    ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadLo(Target::Absolute(1))));
+                    ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::top())));
+                }
+                if target_stack {
+                    ctxt.add_inst(Instruction::StoreToStack(StackOffset::top()));
+                } else {
+                    ctxt.add_inst(Instruction::Discard(StackOffset::new(1)));
+                    ctxt.additional_offset -= 1;
                 }
             },
-            Expression::Operation(op, left, right) => {
+            Expression::Operation(op, left, right, _) => {
                 // if left.is_tail() && right.is_tail() {
                 //     left.emit(ctxt, Reg::C);
                 //     right.emit(ctxt, Reg::ACC)
@@ -184,6 +1189,18 @@ impl Expression {
                         right.emit(ctxt, false); // left on top of stack; right in ACC
                         ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Mul(StackOffset::top())));
                     },
+                    Operator::BitAnd => {
+                        right.emit(ctxt, false); // left on top of stack; right in ACC
+                        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::And(StackOffset::top())));
+                    },
+                    Operator::BitOr => {
+                        right.emit(ctxt, false); // left on top of stack; right in ACC
+                        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Or(StackOffset::top())));
+                    },
+                    Operator::BitXor => {
+                        right.emit(ctxt, false); // left on top of stack; right in ACC
+                        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Xor(StackOffset::top())));
+                    },
                     Operator::Subtract => {
                         right.emit(ctxt, true);
 
@@ -213,23 +1230,6 @@ impl Expression {
                         // ACC left + (~right + 1) == left - right
                         ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::top())));
                     },
-                    Operator::Equals => {
-                        assert!(!target_stack);
-                        right.emit(ctxt, false); // left on top of stack; right in ACC
-
-                        //  left == right --> ACC == 0
-                        //  left != right --> ACC != 0
-                        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Xor(StackOffset::top())));
-                        //  left ^ right == 0 --> left == right
-                    },
-                    Operator::NotEquals => {
-                        //  left == right --> ACC != 0
-                        //  left != right --> ACC == 0
-                        assert!(!target_stack);
-                        right.emit(ctxt, false); // left on top of stack; right in ACC
-
-                        unimplemented!();
-                    }
                     _ => unimplemented!()
                 }
 
@@ -245,206 +1245,781 @@ impl Expression {
         
         ctxt.lines.push(Line::Comment(format!("Evaluated expression: {:?} additional_offset:{}", &self, ctxt.additional_offset)));
     }
+
+    // pushes the value as 16 bits: the low byte on top and the high byte below it
+    fn emit_wide(&self, ctxt: &mut FunctionContext) {
+        ctxt.lines.push(Line::Comment(format!("Evaluating wide expression: {:?} additional_offset:{}", &self, ctxt.additional_offset)));
+
+        if let Some(n) = self.literal() {
+            ctxt.load_constant((n >> 8) as u8, true);
+            ctxt.load_constant(n as u8, true);
+            ctxt.additional_offset += 2;
+            return;
+        }
+
+        match self {
+            Expression::Number(..) => unreachable!(),
+            _ if self.ty(&ctxt.scope) == Type::I8 => {
+                self.emit(ctxt, true);
+
+                // sp+0 value
+                // sp+1 value, replaced by ff when the value is negative and 0 otherwise
+                ctxt.add_inst(Instruction::WithPush(PushableInstruction::LoadFromStack(StackOffset::top())));
+                ctxt.additional_offset += 1;
+                let positive = ctxt.new_label("SIGN");
+                ctxt.load_constant(0x80, false);
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::And(StackOffset::top())));
+                ctxt.add_inst(Instruction::Jz(Target::Label(positive.clone())));
+                ctxt.load_constant(0xff, false);
+                ctxt.lines.push(Line::Label(positive));
+                ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(1)));
+            },
+            _ if !self.ty(&ctxt.scope).is_wide() => {
+                // unsigned values and truth values are zero extended
+                ctxt.load_constant(0, true);
+                ctxt.additional_offset += 1;
+                self.emit(ctxt, true);
+            },
+            Expression::Ident(name, _) => ctxt.load_wide(name),
+            Expression::Cast(operand, _, _) => operand.emit_wide(ctxt),
+            Expression::Deref(pointer, _) => {
+                pointer.emit(ctxt, true);
+
+                // sp+0 address, replaced by the low byte
+                // sp+1 address, replaced by the high byte
+                ctxt.add_inst(Instruction::WithPush(PushableInstruction::LoadFromStack(StackOffset::top())));
+                ctxt.additional_offset += 1;
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadLo(Target::Absolute(1))));
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::new(1))));
+                ctxt.add_inst(Instruction::StoreAddr);
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadMem));
+                ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(1)));
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::top())));
+                ctxt.add_inst(Instruction::StoreAddr);
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadMem));
+                ctxt.add_inst(Instruction::StoreToStack(StackOffset::top()));
+            },
+            Expression::Call(function, parameters, _) => {
+                let (types, returns) = ctxt.scope.functions[function].clone();
+                let parameters : Vec<(&Expression, Type)> = parameters.iter().zip(types).collect();
+                Expression::emit_call(ctxt, function, &parameters, returns, true);
+            },
+            Expression::Unary(op, operand, _) => {
+                if *op == UnaryOperator::Negate {
+                    // -x == 0 - x
+                    ctxt.load_constant(0, true);
+                    ctxt.load_constant(0, true);
+                    ctxt.additional_offset += 2;
+                }
+                operand.emit_wide(ctxt);
+                Expression::complement_wide(ctxt);
+                if *op == UnaryOperator::Negate {
+                    Expression::add_wide(ctxt, 1);
+                    ctxt.discard(2);
+                    ctxt.additional_offset -= 2;
+                }
+            },
+            Expression::Operation(Operator::Multiply, left, right, _) => {
+                let parameters = [(&**left, Type::U16), (&**right, Type::U16)];
+                Expression::emit_call(ctxt, "__mul16", &parameters, Type::U16, true);
+            },
+            Expression::Operation(op, left, right, _) => {
+                left.emit_wide(ctxt);
+                right.emit_wide(ctxt);
+
+                // sp+0 right lo
+                // sp+1 right hi
+                // sp+2 left lo
+                // sp+3 left hi
+
+                match op {
+                    Operator::Add => Expression::add_wide(ctxt, 0),
+                    Operator::Subtract => {
+                        // left - right == left + ~right + 1
+                        Expression::complement_wide(ctxt);
+                        Expression::add_wide(ctxt, 1);
+                    },
+                    Operator::BitAnd | Operator::BitOr | Operator::BitXor => {
+                        let byte_op = match op {
+                            Operator::BitAnd => PushableInstruction::And,
+                            Operator::BitOr => PushableInstruction::Or,
+                            _ => PushableInstruction::Xor,
+                        };
+                        for o in 0..2 {
+                            ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::new(o))));
+                            ctxt.add_inst(Instruction::WithoutPush(byte_op(StackOffset::new(o + 2))));
+                            ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(o + 2)));
+                        }
+                    },
+                    _ => unreachable!("{:?} is not supported for 16-bit values", op),
+                }
+
+                ctxt.discard(2);
+                ctxt.additional_offset -= 2;
+            },
+            Expression::Index(..) | Expression::AddressOf(..) => unreachable!(),
+        }
+
+        ctxt.lines.push(Line::Comment(format!("Evaluated wide expression: {:?} additional_offset:{}", &self, ctxt.additional_offset)));
+    }
+
+    // complements the 16-bit value on top of the stack
+    fn complement_wide(ctxt: &mut FunctionContext) {
+        for o in 0..2 {
+            ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Not(StackOffset::new(o))));
+            ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(o)));
+        }
+    }
+
+    // adds the 16-bit value on top of the stack and a carry of 0 or 1 into the one below it
+    fn add_wide(ctxt: &mut FunctionContext, carry: u8) {
+        // sp+0 right lo
+        // sp+1 right hi
+        // sp+2 left lo, replaced by the sum
+        // sp+3 left hi, replaced by the sum
+        ctxt.add_inst(Instruction::WithPush(PushableInstruction::LoadFromStack(StackOffset::new(2))));
+        ctxt.add_inst(Instruction::WithPush(PushableInstruction::LoadFromStack(StackOffset::new(1))));
+        ctxt.load_constant(carry, true);
+        ctxt.additional_offset += 3;
+        ctxt.add_with_carry();
+
+        // the carry goes into the high byte
+        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::top())));
+        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::new(4))));
+        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::new(6))));
+        ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(6)));
+        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::new(1))));
+        ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(5)));
+        ctxt.discard(3);
+        ctxt.additional_offset -= 3;
+    }
+
+    // materialise a truth value as 0 or 1
+    fn emit_truth(&self, ctxt: &mut FunctionContext, target_stack: bool) {
+        let false_label = ctxt.new_label("CMP_FALSE");
+        let end_label = ctxt.new_label("CMP_END");
+        self.emit_branch(ctxt, &false_label, false);
+        ctxt.add_inst(Instruction::with_push(target_stack, PushableInstruction::LoadLo(Target::Absolute(1))));
+        ctxt.add_inst(Instruction::Jmp(Target::Label(end_label.clone())));
+        ctxt.lines.push(Line::Label(false_label));
+        ctxt.add_inst(Instruction::with_push(target_stack, PushableInstruction::LoadLo(Target::Absolute(0))));
+        ctxt.lines.push(Line::Label(end_label));
+
+        if target_stack {
+            ctxt.additional_offset += 1;
+        }
+    }
+
+    // jumps to label when the truth of the expression (non-zero) equals jump_when and falls
+    // through otherwise; the stack is balanced on both paths
+    fn emit_branch(&self, ctxt: &mut FunctionContext, label: &str, jump_when: bool) {
+        // ACC holds a value that is non-zero exactly when nonzero_is_true says so
+        let jump = |ctxt: &mut FunctionContext, nonzero_is_true: bool| {
+            let target = Target::Label(label.to_owned());
+            ctxt.add_inst(if nonzero_is_true == jump_when { Instruction::Jnz(target) } else { Instruction::Jz(target) });
+        };
+        match self {
+            Expression::Unary(UnaryOperator::Not, operand, _) => {
+                operand.emit_branch(ctxt, label, !jump_when);
+            },
+            Expression::Cast(operand, Type::Bool, _) => {
+                operand.emit_branch(ctxt, label, jump_when);
+            },
+            Expression::Operation(op @ Operator::And, left, right, _)
+            | Expression::Operation(op @ Operator::Or, left, right, _) => {
+                // && stops at the first false operand, || at the first true one
+                let short_circuit = *op == Operator::Or;
+                if short_circuit == jump_when {
+                    left.emit_branch(ctxt, label, jump_when);
+                    right.emit_branch(ctxt, label, jump_when);
+                } else {
+                    let done = ctxt.new_label("LOGIC_DONE");
+                    left.emit_branch(ctxt, &done, short_circuit);
+                    right.emit_branch(ctxt, label, jump_when);
+                    ctxt.lines.push(Line::Label(done));
+                }
+            },
+            Expression::Operation(op @ Operator::Equals, left, right, _)
+            | Expression::Operation(op @ Operator::NotEquals, left, right, _)
+                if Expression::common_type(left, right, &ctxt.scope).is_wide() => {
+                left.emit_wide(ctxt);
+                right.emit_wide(ctxt);
+
+                // (left lo ^ right lo) | (left hi ^ right hi) == 0 --> left == right
+                for o in 0..2 {
+                    ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::new(o))));
+                    ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Xor(StackOffset::new(o + 2))));
+                    ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(o + 2)));
+                }
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Or(StackOffset::new(2))));
+                ctxt.discard(4);
+                ctxt.additional_offset -= 4;
+
+                jump(ctxt, *op == Operator::NotEquals);
+            },
+            Expression::Operation(op @ Operator::Equals, left, right, _)
+            | Expression::Operation(op @ Operator::NotEquals, left, right, _) => {
+                left.emit(ctxt, true);
+                right.emit(ctxt, false); // left on top of stack; right in ACC
+
+                // left ^ right == 0 --> left == right
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Xor(StackOffset::top())));
+                ctxt.add_inst(Instruction::Discard(StackOffset::new(1)));
+                ctxt.additional_offset -= 1;
+
+                jump(ctxt, *op == Operator::NotEquals);
+            },
+            Expression::Operation(op, left, right, _) if op.relation().is_some() => {
+                let (left_greater, negate) = op.relation().unwrap();
+                Expression::emit_greater(ctxt, left, right, left_greater);
+                jump(ctxt, !negate);
+            },
+            _ if self.ty(&ctxt.scope).is_wide() => {
+                self.emit_wide(ctxt);
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::top())));
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Or(StackOffset::new(1))));
+                ctxt.discard(2);
+                ctxt.additional_offset -= 2;
+                jump(ctxt, true);
+            },
+            _ => {
+                self.emit(ctxt, false);
+                jump(ctxt, true);
+            }
+        }
+    }
+
+    // ACC <- the address of array[index]
+    fn emit_element_address(ctxt: &mut FunctionContext, array: &str, index: &Expression) {
+        index.emit(ctxt, true);
+
+        let length = ctxt.array_length(array);
+        if ctxt.bounds_check && length < 256 {
+            // index < length exactly when length + ~index carries
+            ctxt.load_constant(length as u8, true);
+            ctxt.add_inst(Instruction::WithPush(PushableInstruction::Not(StackOffset::new(1))));
+            ctxt.load_constant(0, true);
+            ctxt.additional_offset += 3;
+            ctxt.add_with_carry();
+            ctxt.add_inst(Instruction::PopDiscard(StackOffset::new(2)));
+            ctxt.additional_offset -= 3;
+            ctxt.add_inst(Instruction::Jz(Target::Label(format!(":{}", BOUNDS_FAIL))));
+        }
+
+        ctxt.load_array_address(array, false);
+        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::top())));
+        ctxt.add_inst(Instruction::Discard(StackOffset::new(1)));
+        ctxt.additional_offset -= 1;
+    }
+
+    // result on top of stack if target_stack; else, in ACC. 16-bit results are always left on the stack
+    fn emit_call(ctxt: &mut FunctionContext, function: &str, args: &[(&Expression, Type)], returns: Type, target_stack: bool) {
+        // save space for result
+        for _ in 0..returns.size() {
+            ctxt.add_inst(Instruction::WithPush(PushableInstruction::Not(StackOffset::top())));
+            ctxt.additional_offset += 1;
+        }
+
+        let regs_to_save : Vec<Reg> = ctxt.regs_touched.iter().cloned().collect();
+
+        if !regs_to_save.is_empty() {
+            unimplemented!();
+            // for r in &regs_to_save {
+            //     ctxt.add_macro(format!("push {}", r));
+            //     ctxt.additional_offset += 1;
+            // }
+        }
+
+        for (a, ty) in args {
+            if ty.is_wide() {
+                a.emit_wide(ctxt);
+            } else {
+                a.emit(ctxt, true);
+            }
+        }
+
+        // the callee's return address goes just below SP; runtime routines don't use the frame pointer
+        let frame_delta = match ctxt.find_local(RETURN_ADDRESS) {
+            LocalStorage::Stack(return_address) => return_address as u8 + 1,
+            _ => unreachable!(),
+        };
+        let moves_frame_pointer = ctxt.frame_pointer && ctxt.scope.functions.contains_key(function);
+        if moves_frame_pointer {
+            ctxt.move_frame_pointer(frame_delta.wrapping_neg());
+        }
+        ctxt.add_macro(format!("call :{}", function));
+        if moves_frame_pointer {
+            ctxt.move_frame_pointer(frame_delta);
+        }
+
+        // discard paramters
+        let args_size : usize = args.iter().map(|(_, ty)| ty.size()).sum();
+        if args_size > 0 {
+            ctxt.discard(args_size);
+            ctxt.additional_offset -= args_size;
+        }
+
+        if !regs_to_save.is_empty() {
+            unimplemented!();
+            // for r in regs_to_save.iter().rev() {
+            //     ctxt.add_macro(format!("pop {}", r));
+            //     ctxt.additional_offset -= 1;
+            // }
+        }
+
+        if !target_stack {
+            assert!(!returns.is_wide());
+            // pop result into ACC
+            ctxt.add_inst(Instruction::PopDiscard(StackOffset::top()));
+            ctxt.additional_offset -= 1;
+        }
+    }
+
+    // ACC <- 1 if left > right (or right > left when !left_greater), else 0.
+    // x > y exactly when x + ~y carries; signed operands are biased by 0x80 first.
+    // The operand type decides whether to compare signed.
+    fn emit_greater(ctxt: &mut FunctionContext, left: &Expression, right: &Expression, left_greater: bool) {
+        let ty = Expression::common_type(left, right, &ctxt.scope);
+        let signed = ty.is_signed();
+        if ty.is_wide() {
+            Expression::emit_greater_wide(ctxt, left, right, signed, left_greater);
+            return;
+        }
+
+        left.emit(ctxt, true);
+        right.emit(ctxt, true);
+
+        // sp+0 right
+        // sp+1 left
+
+        let (greater, smaller) = if left_greater { (1, 0) } else { (0, 1) };
+        Expression::prepare_byte(ctxt, greater, signed, false);
+        Expression::prepare_byte(ctxt, smaller, signed, true);
+        ctxt.load_constant(0, true);
+        ctxt.additional_offset += 1;
+        ctxt.add_with_carry();
+        ctxt.add_inst(Instruction::PopDiscard(StackOffset::new(2)));
+        ctxt.additional_offset -= 3;
+    }
+
+    // complements the stack byte at offset if complement, and biases it by 0x80 if signed;
+    // ~(y ^ 80) == y ^ 7f, so a signed smaller operand costs no more than an unsigned one
+    fn prepare_byte(ctxt: &mut FunctionContext, offset: u8, signed: bool, complement: bool) {
+        match (signed, complement) {
+            (false, false) => return,
+            (false, true) => ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Not(StackOffset::new(offset)))),
+            (true, _) => {
+                ctxt.load_constant(if complement { 0x7f } else { 0x80 }, false);
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Xor(StackOffset::new(offset))));
+            },
+        }
+        ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(offset)));
+    }
+
+    // as emit_greater, with the carry rippling from the low bytes into the high bytes
+    fn emit_greater_wide(ctxt: &mut FunctionContext, left: &Expression, right: &Expression, signed: bool, left_greater: bool) {
+        left.emit_wide(ctxt);
+        right.emit_wide(ctxt);
+
+        // sp+0 right lo
+        // sp+1 right hi
+        // sp+2 left lo
+        // sp+3 left hi
+
+        let (greater, smaller) = if left_greater { (2, 0) } else { (0, 2) };
+        Expression::prepare_byte(ctxt, greater + 1, signed, false);
+        Expression::prepare_byte(ctxt, smaller + 1, signed, true);
+        Expression::prepare_byte(ctxt, smaller, false, true);
+
+        // the low bytes, then the high bytes with the carry out of them in its place
+        ctxt.add_inst(Instruction::WithPush(PushableInstruction::LoadFromStack(StackOffset::new(2))));
+        ctxt.add_inst(Instruction::WithPush(PushableInstruction::LoadFromStack(StackOffset::new(1))));
+        ctxt.load_constant(0, true);
+        ctxt.additional_offset += 3;
+        ctxt.add_with_carry();
+        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::new(6))));
+        ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(2)));
+        ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::new(4))));
+        ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(1)));
+        ctxt.add_with_carry();
+        ctxt.add_inst(Instruction::PopDiscard(StackOffset::new(6)));
+        ctxt.additional_offset -= 7;
+    }
 }
 
-const RESULT : &'static str = "RESULT";
-const EPILOGUE : &'static str = "EPILOGUE";
+const RESULT : &str = "RESULT";
+const RETURN_ADDRESS : &str = "RETURN_ADDRESS";
+// RAM below the I/O page
+const MEMORY_SIZE : usize = IO_BASE as usize;
+// the top byte of RAM holds the address of the running function's return address slot, for
+// reaching stack slots through ADDR; the stack starts below it
+const FRAME_POINTER : u8 = (MEMORY_SIZE - 1) as u8;
+const STACK_TOP : usize = FRAME_POINTER as usize;
+// an array can take all of RAM below the frame pointer, though then nothing else fits
+const MAX_ARRAY_LENGTH : usize = STACK_TOP;
+const BOUNDS_FAIL : &str = "__bounds_fail";
+const EPILOGUE : &str = "EPILOGUE";
 
 #[derive(Debug)]
 enum Statement {
-    Assign {local: String, value: Expression},
-    Call { local: String, function: String, parameters: Vec<Expression> },
-    If {predicate: Expression, when_true: Vec<Statement> },
+    Assign {local: String, value: Expression, span: Span },
+    AssignIndex {array: String, index: Expression, value: Expression, span: Span },
+    AssignDeref {pointer: Expression, value: Expression },
+    LocalArray {name: String, length: usize, span: Span },
+    LocalVariable {name: String, ty: Type, span: Span },
+    If {predicate: Expression, when_true: Vec<Statement>, when_false: Vec<Statement> },
+    While {predicate: Expression, body: Vec<Statement> },
     Return { value: Expression},
-    Load {local: String, address: Expression },
-    Store {local: String, address: Expression },
+    Load {local: String, address: Expression, span: Span },
+    Store {local: String, address: Expression, span: Span },
 }
 
 impl Statement {
-    fn parse(pair: pest::iterators::Pair<Rule>) -> Statement {
+    fn parse(pair: pest::iterators::Pair<Rule>) -> Result<Statement, Diagnostic> {
         assert_eq!(Rule::statement, pair.as_rule());
         let pair = pair.into_inner().next().unwrap();
 
-        match pair.as_rule() {
+        Ok(match pair.as_rule() {
             Rule::assign => {
                 let mut pairs = pair.into_inner();
-                let local = pairs.next().unwrap().as_str().trim().to_owned();
-                let value = Expression::parse(pairs.next().unwrap());
-                Statement::Assign { local, value }
+                let target = pairs.next().unwrap();
+                let value = Expression::parse(pairs.next().unwrap())?;
+                match target.as_rule() {
+                    Rule::ident => {
+                        let span = Span::of(&target);
+                        Statement::Assign { local: target.as_str().trim().to_owned(), value, span }
+                    },
+                    Rule::dereference => {
+                        let (pointer, _) = Expression::parse_operand(&mut target.into_inner().peekable())?;
+                        Statement::AssignDeref { pointer, value }
+                    },
+                    _ => match Expression::parse_index(target)? {
+                        Expression::Index(array, index, span) => Statement::AssignIndex { array, index: *index, value, span },
+                        _ => unreachable!(),
+                    },
+                }
+            },
+            Rule::local_array => {
+                let mut pairs = pair.into_inner();
+                let name = pairs.next().unwrap();
+                let span = Span::of(&name);
+                let length = pairs.next().unwrap();
+                let length = match usize::from_str(length.as_str()) {
+                    Ok(n) if n > 0 && n <= MAX_ARRAY_LENGTH => n,
+                    _ => return Err(array_length_error(Span::of(&length))),
+                };
+                Statement::LocalArray { name: name.as_str().to_owned(), length, span }
+            },
+            Rule::local_variable => {
+                let mut pairs = pair.into_inner();
+                let name = pairs.next().unwrap();
+                let span = Span::of(&name);
+                let ty = Type::parse(pairs.next().unwrap());
+                Statement::LocalVariable { name: name.as_str().to_owned(), ty, span }
             },
             Rule::call => {
+                // CALL x := f(...); is the same as ASSIGN x := f(...);
                 let mut pairs = pair.into_inner();
-                let local = pairs.next().unwrap().as_str().trim().to_owned();
-                let function = pairs.next().unwrap().as_str().to_owned();
-
-                let mut parameters = Vec::new();
-                while let Some(arg) = pairs.next() {
-                    parameters.push(Expression::parse(arg));
-                }
-
-                Statement::Call { local, function, parameters }
+                let local = pairs.next().unwrap();
+                let span = Span::of(&local);
+                let local = local.as_str().trim().to_owned();
+                let value = Expression::parse_call(pairs.next().unwrap())?;
+                Statement::Assign { local, value, span }
             },
-            Rule::if_statement => {
+            Rule::if_statement => Statement::parse_if(pair)?,
+            Rule::while_statement => {
                 let mut pairs = pair.into_inner();
-                let predicate = Expression::parse(pairs.next().unwrap());
-                let mut when_true = Vec::new();
-                while let Some(stmt) = pairs.next() {
-                    when_true.push(Statement::parse(stmt));
+                let predicate = Expression::parse(pairs.next().unwrap())?;
+                let mut body = Vec::new();
+                for stmt in pairs {
+                    body.push(Statement::parse(stmt)?);
                 }
-                Statement::If { predicate, when_true }
+                Statement::While { predicate, body }
+            },
+            Rule::putc => {
+                let value = pair.into_inner().next().unwrap();
+                let span = Span::of(&value);
+                Statement::AssignDeref { pointer: Expression::port(CONSOLE_OUT, span), value: Expression::parse(value)? }
             },
             Rule::return_statement => {
                 let expr = pair.into_inner().next().unwrap();
-                Statement::Return { value: Expression::parse(expr) }
+                Statement::Return { value: Expression::parse(expr)? }
             },
             Rule::load => {
                 let mut pairs = pair.into_inner();
-                let local = pairs.next().unwrap().as_str().trim().to_owned();
-                let address = Expression::parse(pairs.next().unwrap());
-                Statement::Load { local, address }
+                let local = pairs.next().unwrap();
+                let span = Span::of(&local);
+                let local = local.as_str().trim().to_owned();
+                let address = Expression::parse(pairs.next().unwrap())?;
+                Statement::Load { local, address, span }
             },
             Rule::store => {
                 let mut pairs = pair.into_inner();
-                let local = pairs.next().unwrap().as_str().trim().to_owned();
-                let address = Expression::parse(pairs.next().unwrap());
-                Statement::Store { local, address }
+                let local = pairs.next().unwrap();
+                let span = Span::of(&local);
+                let local = local.as_str().trim().to_owned();
+                let address = Expression::parse(pairs.next().unwrap())?;
+                Statement::Store { local, address, span }
             }
             _ => panic!("Unexpected {:?}", pair)
-        }
+        })
     }
 
-    fn emit(&self, ctxt: &mut FunctionContext, function_name: &str) -> () {
-        ctxt.lines.push(Line::Comment(format!("Begin statement {:?}", self)));
+    fn fold(&mut self, constants: &BTreeMap<String, i32>) {
         match self {
-            Statement::Load{local, address} => {
-                address.emit(ctxt, false);
-                ctxt.add_inst(Instruction::StoreAddr);
-                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadMem));
-
-                let local = ctxt.find_local(local);
-                match local {
-                    LocalStorage::Register(r) => {
-                        unimplemented!();
-                    }
-                    LocalStorage::Stack(offset) => {
-                        ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(offset as u8)));
-                    }
-                }
+            Statement::Assign { value, .. } | Statement::Return { value } => value.fold(constants, None),
+            Statement::AssignIndex { index, value, .. } | Statement::AssignDeref { pointer: index, value, .. } => {
+                index.fold(constants, None);
+                value.fold(constants, None);
             },
-            Statement::Store{local, address} => {
-                address.emit(ctxt, false);
-                ctxt.add_inst(Instruction::StoreAddr);
-
-                let local = ctxt.find_local(local);
-                match local {
-                    LocalStorage::Register(r) => {
-                        unimplemented!();
-                    }
-                    LocalStorage::Stack(offset) => {
-                        ctxt.add_inst(Instruction::WithoutPush(
-                            PushableInstruction::LoadFromStack(StackOffset::new(offset as u8))));
-                    }
+            Statement::Load { address, .. } | Statement::Store { address, .. } => address.fold(constants, None),
+            Statement::If { predicate, when_true, when_false } => {
+                predicate.fold(constants, None);
+                for s in when_true.iter_mut().chain(when_false) {
+                    s.fold(constants);
                 }
-                ctxt.add_inst(Instruction::StoreMem);
             },
-            Statement::Assign{local, value} => {
-                let local = ctxt.find_local(local);
-                match local {
-                    LocalStorage::Register(r) => {
-                        unimplemented!();
-                    }
-                    LocalStorage::Stack(offset) => {
-                        value.emit(ctxt, false);
-                        ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(offset as u8)));
-                    }
+            Statement::While { predicate, body } => {
+                predicate.fold(constants, None);
+                for s in body {
+                    s.fold(constants);
                 }
             },
-            Statement::Return{ value } => {
-                value.emit(ctxt, false);
-
-                let result_offset = match ctxt.find_local(RESULT) {
-                    LocalStorage::Register(_) => unimplemented!(),
-                    LocalStorage::Stack(offset) => offset,
-                };
+            Statement::LocalArray { .. } | Statement::LocalVariable { .. } => {},
+        }
+    }
 
-                ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(result_offset as u8)));
+    // every statement in body, including those nested in IF and WHILE blocks
+    fn walk<'a>(body: &'a [Statement], f: &mut dyn FnMut(&'a Statement)) {
+        for s in body {
+            f(s);
+            match s {
+                Statement::If { when_true, when_false, .. } => {
+                    Statement::walk(when_true, f);
+                    Statement::walk(when_false, f);
+                },
+                Statement::While { body, .. } => Statement::walk(body, f),
+                _ => {},
+            }
+        }
+    }
 
+    fn parse_if(pair: pest::iterators::Pair<Rule>) -> Result<Statement, Diagnostic> {
+        assert_eq!(Rule::if_statement, pair.as_rule());
+        let mut pairs = pair.into_inner();
+        let predicate = Expression::parse(pairs.next().unwrap())?;
+        let mut when_true = Vec::new();
+        let mut when_false = Vec::new();
+        for p in pairs {
+            match p.as_rule() {
+                Rule::statement => when_true.push(Statement::parse(p)?),
+                Rule::else_clause => {
+                    // ELSE IF is just an ELSE block holding a single IF
+                    for p in p.into_inner() {
+                        when_false.push(match p.as_rule() {
+                            Rule::if_statement => Statement::parse_if(p)?,
+                            _ => Statement::parse(p)?,
+                        });
+                    }
+                },
+                _ => panic!("Unexpected {:?}", p)
+            }
+        }
+        Ok(Statement::If { predicate, when_true, when_false })
+    }
 
-                if ctxt.additional_offset != 0 {
-                    ctxt.add_inst(Instruction::Discard(StackOffset::new(ctxt.additional_offset as u8)));
+    fn check(&self, f: &Function, program: &Program, diagnostics: &mut Vec<Diagnostic>) {
+        match self {
+            Statement::Assign { local, value, span } => {
+                check_assignable(local, *span, f, program, diagnostics);
+                value.check(f, program, diagnostics);
+            },
+            Statement::AssignIndex { array, index, value, span } => {
+                check_array(array, *span, f, program, diagnostics);
+                index.check(f, program, diagnostics);
+                value.check(f, program, diagnostics);
+            },
+            Statement::AssignDeref { pointer, value, .. } => {
+                pointer.check(f, program, diagnostics);
+                value.check(f, program, diagnostics);
+            },
+            Statement::LocalArray { .. } | Statement::LocalVariable { .. } => {},
+            Statement::Return { value } => value.check(f, program, diagnostics),
+            Statement::Load { local, address, span } => {
+                check_assignable(local, *span, f, program, diagnostics);
+                address.check(f, program, diagnostics);
+            },
+            Statement::Store { address, .. } => {
+                address.check(f, program, diagnostics);
+            },
+            Statement::If { predicate, when_true, when_false } => {
+                predicate.check(f, program, diagnostics);
+                for s in when_true.iter().chain(when_false) {
+                    s.check(f, program, diagnostics);
                 }
-                ctxt.add_inst(Instruction::Jmp(Target::Label(
-                    format!(":{}__{}", function_name, EPILOGUE)
-                )));
             },
-            Statement::Call{ local, function, parameters} => { 
-
-                assert_eq!(ctxt.additional_offset, 0);
-
-                // save space for result
-                ctxt.add_inst(Instruction::WithPush(PushableInstruction::Not(StackOffset::top())));
-                ctxt.additional_offset += 1;
-
-                let regs_to_save : Vec<Reg> = ctxt.regs_touched.iter().cloned().collect();
+            Statement::While { predicate, body } => {
+                predicate.check(f, program, diagnostics);
+                for s in body {
+                    s.check(f, program, diagnostics);
+                }
+            },
+        }
+    }
 
-                for r in &regs_to_save {
-                    unimplemented!();
-                    // ctxt.add_macro(format!("push {}", r));
-                    // ctxt.additional_offset += 1;
+    fn type_check(&self, scope: &Scope, diagnostics: &mut Vec<Diagnostic>) {
+        match self {
+            Statement::Assign { local, value, .. } => value.expect(scope.variable(local), scope, diagnostics),
+            Statement::AssignIndex { index, value, .. } => {
+                index.expect(Type::U8, scope, diagnostics);
+                value.expect(Type::U8, scope, diagnostics);
+            },
+            Statement::AssignDeref { pointer, value, .. } => {
+                let ty = pointer.pointee(scope, diagnostics);
+                value.expect(ty, scope, diagnostics);
+            },
+            Statement::LocalArray { .. } | Statement::LocalVariable { .. } => {},
+            Statement::Return { value } => value.expect(scope.variable(RESULT), scope, diagnostics),
+            Statement::Load { local, address, span } | Statement::Store { local, address, span } => {
+                let ty = scope.variable(local);
+                if ty.size() != 1 {
+                    diagnostics.push(Diagnostic::new(*span, format!("LOAD and STORE move a single byte but '{}' is {}", local, ty)));
+                }
+                address.expect_address(scope, diagnostics);
+            },
+            Statement::If { predicate, when_true, when_false } => {
+                // any value can be tested
+                predicate.infer(scope, diagnostics);
+                for s in when_true.iter().chain(when_false) {
+                    s.type_check(scope, diagnostics);
                 }
+            },
+            Statement::While { predicate, body } => {
+                predicate.infer(scope, diagnostics);
+                for s in body {
+                    s.type_check(scope, diagnostics);
+                }
+            },
+        }
+    }
 
-                for p in parameters {
-                    p.emit(ctxt, true);
+    fn emit(&self, ctxt: &mut FunctionContext, function_name: &str) {
+        ctxt.lines.push(Line::Comment(format!("Begin statement {:?}", self)));
+        match self {
+            Statement::Load{local, address, ..} => {
+                address.emit(ctxt, false);
+                ctxt.add_inst(Instruction::StoreAddr);
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadMem));
+                ctxt.store_local(local);
+            },
+            Statement::Store{local, address, ..} => {
+                // the value first: loading a global uses ADDR too
+                ctxt.load_local(local, true);
+                address.emit(ctxt, false);
+                ctxt.add_inst(Instruction::StoreAddr);
+                ctxt.add_inst(Instruction::PopDiscard(StackOffset::top()));
+                ctxt.additional_offset -= 1;
+                ctxt.add_inst(Instruction::StoreMem);
+            },
+            Statement::Assign{local, value, ..} if ctxt.scope.variable(local).is_wide() => {
+                value.emit_wide(ctxt);
+                ctxt.store_wide(local);
+            },
+            Statement::Assign{local, value, ..} => {
+                value.emit(ctxt, false);
+                ctxt.store_local(local);
+            },
+            Statement::AssignIndex{array, index, value, ..} => {
+                value.emit(ctxt, true);
+                Expression::emit_element_address(ctxt, array, index);
+                ctxt.add_inst(Instruction::StoreAddr);
+                ctxt.add_inst(Instruction::PopDiscard(StackOffset::top()));
+                ctxt.additional_offset -= 1;
+                ctxt.add_inst(Instruction::StoreMem);
+            },
+            Statement::AssignDeref{pointer, value, ..}
+                if matches!(pointer.ty(&ctxt.scope), Type::Pointer(pointee) if pointee.is_wide()) => {
+                value.emit_wide(ctxt);
+                pointer.emit(ctxt, true);
+
+                // sp+0 address
+                // sp+1 value lo
+                // sp+2 value hi
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::top())));
+                ctxt.add_inst(Instruction::StoreAddr);
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::new(1))));
+                ctxt.add_inst(Instruction::StoreMem);
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadLo(Target::Absolute(1))));
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::Add(StackOffset::top())));
+                ctxt.add_inst(Instruction::StoreAddr);
+                ctxt.add_inst(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::new(2))));
+                ctxt.add_inst(Instruction::StoreMem);
+                ctxt.discard(3);
+                ctxt.additional_offset -= 3;
+            },
+            Statement::AssignDeref{pointer, value, ..} => {
+                value.emit(ctxt, true);
+                pointer.emit(ctxt, false);
+                ctxt.add_inst(Instruction::StoreAddr);
+                ctxt.add_inst(Instruction::PopDiscard(StackOffset::top()));
+                ctxt.additional_offset -= 1;
+                ctxt.add_inst(Instruction::StoreMem);
+            },
+            Statement::LocalArray{..} | Statement::LocalVariable{..} => {
+                // allocated with the rest of the frame
+            },
+            Statement::Return{ value } => {
+                if ctxt.scope.variable(RESULT).is_wide() {
+                    value.emit_wide(ctxt);
+                    ctxt.store_wide(RESULT);
+                } else {
+                    value.emit(ctxt, false);
+                    ctxt.store_local(RESULT);
                 }
 
-                ctxt.add_macro(format!("call :{}", function));
+                let additional_offset = ctxt.additional_offset;
+                ctxt.discard(additional_offset);
+                ctxt.add_inst(Instruction::Jmp(Target::Label(
+                    format!(":{}__{}", function_name, EPILOGUE)
+                )));
+            },
+            Statement::If{predicate, when_true, when_false} => {
+                let jump_label = ctxt.new_label("IF_SKIP");
+                let end_label = ctxt.new_label("IF_END");
 
-                // discard paramters
-                ctxt.add_inst(Instruction::Discard(StackOffset::new(parameters.len() as u8)));
-                ctxt.additional_offset -= parameters.len();
+                predicate.emit_branch(ctxt, &jump_label, false);
 
-                for r in regs_to_save.iter().rev() {
-                    unimplemented!();
-                    // ctxt.add_macro(format!("pop {}", r));
-                    // ctxt.additional_offset -= 1;
+                // let mut count = 0;
+                for s in when_true {
+                    // let scope = format!("{}_stmt{}", scope, count);
+                    s.emit(ctxt, function_name);
+                    // count += 1;
                 }
 
-                // pop result into b
-                ctxt.add_inst(Instruction::PopDiscard(StackOffset::top()));
-                ctxt.additional_offset -= 1;
-
-                // stack is now back to normal
-
-                let local = ctxt.find_local(local);
-                match local {
-                    LocalStorage::Register(r) => {
-                        unimplemented!();
-
-                    },
-                    LocalStorage::Stack(offset) => {
-                        ctxt.add_inst(Instruction::StoreToStack(StackOffset::new(offset as u8)));
+                if when_false.is_empty() {
+                    ctxt.lines.push(Line::Label(jump_label));
+                } else {
+                    ctxt.add_inst(Instruction::Jmp(Target::Label(end_label.clone())));
+                    ctxt.lines.push(Line::Label(jump_label));
+                    for s in when_false {
+                        s.emit(ctxt, function_name);
                     }
+                    ctxt.lines.push(Line::Label(end_label));
                 }
             },
-            Statement::If{predicate, when_true} => {
-                let if_skip = "IF_SKIP";
-                predicate.emit(ctxt, false); // result in ACC
+            Statement::While{predicate, body} => {
+                let top_label = ctxt.new_label("WHILE_TOP");
+                let end_label = ctxt.new_label("WHILE_END");
 
-                let jump_label = format!("{}_{}_{}", function_name, if_skip, ctxt.block_counter);
+                ctxt.lines.push(Line::Label(top_label.clone()));
+                predicate.emit_branch(ctxt, &end_label, false);
 
-                ctxt.block_counter += 1;
-
-                // WEIRD: interpret 0 as true
-                ctxt.add_inst(Instruction::Jnz(Target::Label(format!(":{}", &jump_label))));
-
-                // let mut count = 0;
-                for s in when_true {
-                    // let scope = format!("{}_stmt{}", scope, count);
+                for s in body {
                     s.emit(ctxt, function_name);
-                    // count += 1;
                 }
-                
-                ctxt.lines.push(Line::Label(format!(":{}", &jump_label)));
+
+                ctxt.add_inst(Instruction::Jmp(Target::Label(top_label)));
+                ctxt.lines.push(Line::Label(end_label));
             },
         }
         ctxt.lines.push(Line::Comment(format!("Done  statement {:?}", self)));
@@ -453,62 +2028,250 @@ impl Statement {
 
 #[derive(Clone, Copy, Debug)]
 enum LocalStorage {
+    #[allow(dead_code)]
     Register(Reg),
     Stack(usize),
+    Global(u8),
+    // arrays evaluate to their address: (address or offset of element 0, length)
+    GlobalArray(u8, usize),
+    StackArray(usize, usize),
+}
+
+#[derive(Debug)]
+struct Global {
+    span: Span,
+    address: u8,
+    length: Option<usize>,
+    ty: Type,
+    // bytes stored by the startup code; empty when the global is not initialised
+    init: Vec<u8>,
+}
+
+impl Global {
+    fn size(&self) -> usize {
+        self.length.unwrap_or_else(|| self.ty.size())
+    }
+}
+
+struct Program {
+    span: Span,
+    globals: BTreeMap<String, Global>,
+    constants: BTreeMap<String, i32>,
+    functions: BTreeMap<String, Function>,
+}
+
+impl Program {
+    // globals are allocated upwards from 0; the stack grows down from the top of memory
+    fn globals_size(&self) -> usize {
+        self.globals.values().map(Global::size).sum()
+    }
+
+    // stores the initial contents of globals; runs before main. RAM starts out zeroed, so
+    // only the bytes that aren't zero need storing.
+    fn initialise(&self) -> Vec<Line> {
+        let mut lines = Vec::new();
+        for (name, g) in self.globals.iter().filter(|(_, g)| g.init.iter().any(|b| *b != 0)) {
+            lines.push(Line::Comment(format!("initialise {}", name)));
+            for (i, byte) in g.init.iter().enumerate().filter(|(_, b)| **b != 0) {
+                lines.extend(load_constant((g.address as usize + i) as u8, false).into_iter().map(Line::Instruction));
+                lines.push(Line::Instruction(Instruction::StoreAddr));
+                lines.extend(load_constant(*byte, false).into_iter().map(Line::Instruction));
+                lines.push(Line::Instruction(Instruction::StoreMem));
+            }
+        }
+        lines
+    }
+}
+
+fn check_assignable(local: &str, span: Span, f: &Function, program: &Program, diagnostics: &mut Vec<Diagnostic>) {
+    if f.is_array(local, program) == Some(true) {
+        diagnostics.push(Diagnostic::new(span, format!("cannot assign to array '{}'", local)));
+    } else if f.is_constant(local, program) {
+        diagnostics.push(Diagnostic::new(span, format!("cannot assign to constant '{}'", local)));
+    }
+}
+
+fn check_array(array: &str, span: Span, f: &Function, program: &Program, diagnostics: &mut Vec<Diagnostic>) {
+    match f.is_array(array, program) {
+        Some(true) => {},
+        Some(false) => diagnostics.push(Diagnostic::new(span, format!("'{}' is not an array", array))),
+        None => diagnostics.push(Diagnostic::new(span, format!("undefined variable '{}'", array))),
+    }
 }
 
 #[derive(Debug)]
 struct Function {
     name: String,
+    span: Span,
     args: Vec<String>,
     locals: BTreeSet<String>,
+    arrays: BTreeMap<String, usize>,
+    // parameters and locals declared with LOCAL; other locals are u8
+    types: BTreeMap<String, Type>,
+    returns: Type,
     body: Vec<Statement>,
 }
 
 impl Function {
-    fn parse(pair: pest::iterators::Pair<Rule>) -> Function {
+    fn parse(pair: pest::iterators::Pair<Rule>, globals: &BTreeMap<String, Global>, constants: &BTreeMap<String, i32>) -> Result<Function, Diagnostic> {
         assert_eq!(Rule::function, pair.as_rule());
 
         let mut args = Vec::new();
 
         let mut pairs = pair.into_inner();
 
-        let name = pairs.next().unwrap().as_str().to_owned();
+        let name = pairs.next().unwrap();
+        let span = Span::of(&name);
+        let name = name.as_str().to_owned();
+
+        let mut types = BTreeMap::new();
+        for parameter in pairs.next().unwrap().into_inner() {
+            let mut parameter = parameter.into_inner();
+            let arg = parameter.next().unwrap();
+            if args.iter().any(|a| a == arg.as_str()) {
+                return Err(Diagnostic::new(Span::of(&arg), format!("duplicate parameter '{}'", arg.as_str())));
+            }
+            args.push(arg.as_str().to_owned());
+            types.insert(arg.as_str().to_owned(), parameter.next().map_or(Type::U8, Type::parse));
+        }
 
-        for arg in pairs.next().unwrap().into_inner() {
-            let arg = arg.as_str();
-            args.push(arg.to_owned());
+        let mut returns = Type::U8;
+        let mut next = pairs.next().unwrap();
+        if next.as_rule() == Rule::type_name {
+            returns = Type::parse(next);
+            next = pairs.next().unwrap();
         }
 
-        let body : Vec<Statement> = pairs.next().unwrap().into_inner().map(|p| Statement::parse(p)).collect();
+        let body = next.into_inner()
+            .map(Statement::parse)
+            .collect::<Result<Vec<Statement>, Diagnostic>>()?;
 
-        // find locals
+        // arrays and typed locals are declared with LOCAL; any other name that is written to is a u8 local
+        let mut arrays = BTreeMap::new();
         let mut locals = BTreeSet::new();
+        let mut duplicate = None;
+        Statement::walk(&body, &mut |s| match s {
+            Statement::LocalArray { name, length, span } => {
+                let defined = types.contains_key(name) || arrays.insert(name.clone(), *length).is_some();
+                if defined {
+                    duplicate.get_or_insert(Diagnostic::new(*span, format!("'{}' is already defined", name)));
+                }
+            },
+            Statement::LocalVariable { name, ty, span } => {
+                let defined = arrays.contains_key(name) || types.insert(name.clone(), *ty).is_some();
+                if defined {
+                    duplicate.get_or_insert(Diagnostic::new(*span, format!("'{}' is already defined", name)));
+                }
+                locals.insert(name.clone());
+            },
+            _ => {},
+        });
+        if let Some(d) = duplicate {
+            return Err(d);
+        }
 
-        fn add_locals(s: &Statement, args: &Vec<String>, locals: &mut BTreeSet<String>) {
-            match s {
-                Statement::Assign{local, value:_} 
-                | Statement::Load{local, address:_}
-                | Statement::Store{local, address:_ }
-                | Statement::Call{ local, function:_, parameters:_ } => { 
-                    if !args.contains(local) {
-                        locals.insert(local.clone()); 
-                    }
-                },
-                Statement::Return{ value:_ } => {},
-                Statement::If{ predicate:_, when_true:ss } => {
-                    for s in ss {
-                        add_locals(s, args, locals);
-                    }
-                },
-            }
-        };
+        Statement::walk(&body, &mut |s| match s {
+            Statement::Assign{local, .. }
+            | Statement::Load{local, .. }
+            | Statement::Store{local, .. }
+                if !args.contains(local) && !arrays.contains_key(local)
+                    && !globals.contains_key(local) && !constants.contains_key(local) => {
+                locals.insert(local.clone());
+            },
+            _ => {},
+        });
+
+        Ok(Function { name, span, args, locals, arrays, types, returns, body })
+    }
+
+    // Some(true) for arrays, Some(false) for scalars and None if the name is undefined
+    fn is_array(&self, name: &str, program: &Program) -> Option<bool> {
+        if self.args.iter().any(|a| a == name) || self.locals.contains(name) {
+            Some(false)
+        } else if self.arrays.contains_key(name) {
+            Some(true)
+        } else if program.constants.contains_key(name) {
+            Some(false)
+        } else {
+            program.globals.get(name).map(|g| g.length.is_some())
+        }
+    }
+
+    // true when the name refers to a global rather than a parameter or local
+    fn is_global(&self, name: &str, program: &Program) -> bool {
+        !self.args.iter().any(|a| a == name) && !self.locals.contains(name) && !self.arrays.contains_key(name)
+            && program.globals.contains_key(name)
+    }
+
+    fn is_constant(&self, name: &str, program: &Program) -> bool {
+        !self.types.contains_key(name) && !self.arrays.contains_key(name) && program.constants.contains_key(name)
+    }
+
+    // constants are substituted except where a parameter or declared local shadows them
+    fn fold(&mut self, constants: &BTreeMap<String, i32>) {
+        let mut constants = constants.clone();
+        constants.retain(|name, _| !self.types.contains_key(name) && !self.arrays.contains_key(name));
+        for s in &mut self.body {
+            s.fold(&constants);
+        }
+    }
+
+    fn size_of(&self, name: &str) -> usize {
+        self.types.get(name).map_or(1, Type::size)
+    }
+
+    // stack slots for the locals, which the function allocates itself
+    fn locals_size(&self) -> usize {
+        self.locals.iter().map(|l| self.size_of(l)).sum::<usize>() + self.arrays.values().sum::<usize>()
+    }
+
+    fn scope(&self, program: &Program) -> Scope {
+        let mut variables = BTreeMap::new();
+        for (name, g) in &program.globals {
+            variables.insert(name.clone(), g.ty);
+        }
+        for name in self.args.iter().chain(&self.locals) {
+            variables.insert(name.clone(), self.types.get(name).cloned().unwrap_or(Type::U8));
+        }
+        for name in self.arrays.keys() {
+            variables.insert(name.clone(), Type::U8);
+        }
+        variables.insert(RESULT.to_owned(), self.returns);
+
+        let functions = program.functions.iter()
+            .map(|(name, f)| (name.clone(), (f.args.iter().map(|a| f.types[a]).collect(), f.returns)))
+            .collect();
+
+        Scope { variables, functions }
+    }
 
-        for s in body.iter() {
-            add_locals(s, &args, &mut locals);
+    fn check(&self, program: &Program) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        if self.name == "main" && !self.args.is_empty() {
+            diagnostics.push(Diagnostic::new(self.span, "main cannot take parameters"));
         }
+        let frame_size = self.returns.size() + self.args.iter().map(|a| self.size_of(a)).sum::<usize>()
+            + 1 // return address
+            + self.locals_size();
+        let stack_size = STACK_TOP.saturating_sub(program.globals_size());
+        if frame_size > stack_size {
+            diagnostics.push(Diagnostic::new(self.span, format!("{} needs a {} byte stack frame but the stack is only {} bytes",
+                self.name, frame_size, stack_size)));
+        }
+        for s in &self.body {
+            s.check(self, program, &mut diagnostics);
+        }
+        diagnostics
+    }
 
-        Function { name, args, locals, body }
+    // run once check has found every name, so the scope is complete
+    fn type_check(&self, program: &Program) -> Vec<Diagnostic> {
+        let scope = self.scope(program);
+        let mut diagnostics = Vec::new();
+        for s in &self.body {
+            s.type_check(&scope, &mut diagnostics);
+        }
+        diagnostics
     }
 
     /*
@@ -524,45 +2287,71 @@ impl Function {
             RESULT
     */
 
-    fn emit(&self) -> FunctionContext {
+    fn emit(&self, program: &Program, bounds_check: bool, frame_pointer: bool) -> FunctionContext {
         let mut ctxt = FunctionContext {
             stack: BTreeMap::new(),
             lines: Vec::new(),
             additional_offset: 0,
             regs_touched: BTreeSet::new(),
             block_counter: 0,
+            function_name: self.name.clone(),
+            bounds_check,
+            scope: self.scope(program),
+            frame_pointer,
+            uses_frame_pointer: false,
         };
+        for (name, g) in &program.globals {
+            let storage = match g.length {
+                Some(length) => LocalStorage::GlobalArray(g.address, length),
+                None => LocalStorage::Global(g.address),
+            };
+            ctxt.stack.insert(name.clone(), storage);
+        }
+
         ctxt.lines.push(Line::Comment(format!("# Function: {}", &self.name)));
         ctxt.lines.push(Line::Label(format!(":{}", &self.name)));
 
         let max_register_locals = 0;
 
         let register_local_count = std::cmp::min(max_register_locals, self.locals.len());
-        let stack_local_count = self.locals.len() - register_local_count;
+        let stack_local_count = self.locals_size();
 
-        let stack_size = 0
-            + 1 // result
-            + self.args.len()
+        let stack_size = self.returns.size()
+            + self.args.iter().map(|a| self.size_of(a)).sum::<usize>()
             + 1 // return address
             + stack_local_count;
         let mut offset = (stack_size - 1) as isize;
 
+        // 16-bit values are little-endian, so they are placed by their low (lower) slot
+        offset -= self.returns.size() as isize - 1;
         ctxt.lines.push(Line::Comment(format!("# sp+{} -> {}", offset, RESULT)));
         ctxt.stack.insert(RESULT.to_owned(), LocalStorage::Stack(offset as usize));
         offset -= 1;
 
         for arg in &self.args {
+            offset -= self.size_of(arg) as isize - 1;
             ctxt.lines.push(Line::Comment(format!("# sp+{} -> {}", offset, arg)));
             ctxt.stack.insert(arg.clone(), LocalStorage::Stack(offset as usize));
             offset -= 1;
         }
 
-        ctxt.lines.push(Line::Comment(format!("# sp+{} -> {}", offset, "RETURN_ADDRESS")));
-        ctxt.stack.insert("RETURN_ADDRESS".to_owned(), LocalStorage::Stack(offset as usize));
+        ctxt.lines.push(Line::Comment(format!("# sp+{} -> {}", offset, RETURN_ADDRESS)));
+        ctxt.stack.insert(RETURN_ADDRESS.to_owned(), LocalStorage::Stack(offset as usize));
         offset -= 1;
 
         // offset -= register_local_count as isize;
 
+        // arrays go above the scalar locals, which then stay within reach of a single instruction
+        for (a, length) in &self.arrays {
+            // element 0 is the lowest slot so that indexes count up in memory
+            offset -= *length as isize - 1;
+            let storage = LocalStorage::StackArray(offset as usize, *length);
+            offset -= 1;
+
+            ctxt.lines.push(Line::Comment(format!("# {:?} -> {}", storage, a)));
+            ctxt.stack.insert(a.clone(), storage);
+        }
+
         for (count, l) in self.locals.iter().enumerate() {
             let storage = match count {
                 count if count < register_local_count => {
@@ -571,6 +2360,7 @@ impl Function {
                     // LocalStorage::Register(reg)
                 },
                 _ => {
+                    offset -= self.size_of(l) as isize - 1;
                     let s = LocalStorage::Stack(offset as usize);
                     offset -= 1;
                     s
@@ -594,7 +2384,7 @@ impl Function {
 
         if stack_local_count > 0 {
             ctxt.lines.push(Line::Comment("create stack space".to_owned()));
-            ctxt.add_inst(Instruction::Alloc(StackOffset::new(stack_local_count as u8)));
+            ctxt.alloc(stack_local_count);
         }
 
         // let mut count = 0;
@@ -606,7 +2396,7 @@ impl Function {
          
         ctxt.lines.push(Line::Label(format!(":{}__{}", &self.name, EPILOGUE)));
         if stack_local_count > 0 {
-            ctxt.add_inst(Instruction::Discard(StackOffset::new(stack_local_count as u8)));
+            ctxt.discard(stack_local_count);
         }
 
         // if register_local_count > 0 {
@@ -617,63 +2407,839 @@ impl Function {
         //     }
         // }
 
-        ctxt.add_macro(format!("ret"));
+        ctxt.add_macro("ret".to_owned());
 
         ctxt
     }
 }
 
 
-fn main() -> Result<(), std::io::Error> {
-    let input = {
-        let mut s = String::new();
-        let stdin = io::stdin();
-        stdin.lock().read_to_string(&mut s)?;
-        s
+fn parse_global(pair: pest::iterators::Pair<Rule>, address: usize, constants: &BTreeMap<String, i32>) -> Result<(String, Global), Diagnostic> {
+    assert_eq!(Rule::global, pair.as_rule());
+    let mut pairs = pair.into_inner().peekable();
+    let name = pairs.next().unwrap();
+    let span = Span::of(&name);
+
+    let declared = pairs.next_if(|p| p.as_rule() != Rule::initialiser);
+    let (mut length, ty) = match declared.clone() {
+        Some(ty) if ty.as_rule() == Rule::type_name => (None, Type::parse(ty)),
+        Some(length) => match usize::from_str(length.as_str()) {
+            Ok(n) if n > 0 && n <= MAX_ARRAY_LENGTH => (Some(n), Type::U8),
+            _ => return Err(array_length_error(Span::of(&length))),
+        },
+        None => (None, Type::U8),
     };
 
+    let mut init = Vec::new();
+    if let Some(initialiser) = pairs.next() {
+        let init_span = Span::of(&initialiser);
+        init = parse_initialiser(initialiser, constants)?;
+        match (declared, length) {
+            // the initialiser gives the length
+            (None, _) if init.is_empty() || init.len() > MAX_ARRAY_LENGTH => return Err(array_length_error(init_span)),
+            (None, _) => length = Some(init.len()),
+            (Some(_), Some(n)) if init.len() > n => {
+                return Err(Diagnostic::new(init_span, format!("initialiser is {} bytes but '{}' is only {}", init.len(), name.as_str(), n)));
+            },
+            (Some(_), Some(n)) => init.resize(n, 0),
+            (Some(_), None) => return Err(Diagnostic::new(init_span, "only arrays can be initialised")),
+        }
+    }
+
+    // globals are allocated one after another, so this is their total size so far
+    let global = Global { span, address: address as u8, length, ty, init };
+    if address + global.size() > STACK_TOP {
+        return Err(Diagnostic::new(span, format!("global '{}' does not fit in memory: the globals need {} bytes but RAM holds {}",
+            name.as_str(), address + global.size(), STACK_TOP)));
+    }
+    Ok((name.as_str().to_owned(), global))
+}
+
+fn array_length_error(span: Span) -> Diagnostic {
+    Diagnostic::new(span, format!("array length must be between 1 and {}", MAX_ARRAY_LENGTH))
+}
+
+// the bytes a string or byte array literal puts in memory
+fn parse_initialiser(pair: pest::iterators::Pair<Rule>, constants: &BTreeMap<String, i32>) -> Result<Vec<u8>, Diagnostic> {
+    assert_eq!(Rule::initialiser, pair.as_rule());
+    let pair = pair.into_inner().next().unwrap();
+    let span = Span::of(&pair);
+    match pair.as_rule() {
+        Rule::string => {
+            let text = pair.as_str();
+            let mut bytes = Vec::new();
+            let mut chars = text[1..text.len() - 1].chars();
+            while let Some(c) = chars.next() {
+                let c = match c {
+                    // the grammar guarantees a character after every backslash
+                    '\\' => match chars.next().unwrap() {
+                        'n' => '\n',
+                        't' => '\t',
+                        '0' => '\0',
+                        c @ '\\' | c @ '"' => c,
+                        c => return Err(Diagnostic::new(span, format!("unknown escape '\\{}'", c))),
+                    },
+                    c => c,
+                };
+                bytes.extend(c.encode_utf8(&mut [0; 4]).bytes());
+            }
+            bytes.push(0);
+            Ok(bytes)
+        },
+        Rule::byte_array => pair.into_inner().map(|element| {
+            let span = Span::of(&element);
+            let mut value = Expression::parse(element)?;
+            value.fold(constants, Some(Type::U8));
+            match value {
+                Expression::Number(n, _) if Type::U8.range().contains(&n) => Ok(n as u8),
+                Expression::Number(n, _) => Err(Diagnostic::new(span, format!("literal {} is out of range for u8", n))),
+                _ => Err(Diagnostic::new(span, "not a constant expression")),
+            }
+        }).collect(),
+        _ => unreachable!(),
+    }
+}
+
+fn parse_constant(pair: pest::iterators::Pair<Rule>, constants: &BTreeMap<String, i32>) -> Result<(String, i32, Span), Diagnostic> {
+    assert_eq!(Rule::constant, pair.as_rule());
+    let mut pairs = pair.into_inner();
+    let name = pairs.next().unwrap();
+    let span = Span::of(&name);
+    let value = pairs.next().unwrap();
+    let value_span = Span::of(&value);
+
+    // a constant has no type, so a complement in it is 8 bits unless a cast says otherwise
+    let mut value = Expression::parse(value)?;
+    value.fold(constants, Some(Type::U8));
+    match value {
+        Expression::Number(n, _) => Ok((name.as_str().to_owned(), n, span)),
+        _ => Err(Diagnostic::new(value_span, "not a constant expression")),
+    }
+}
+
+fn parse_program(mut program: pest::iterators::Pairs<Rule>) -> Result<Program, Vec<Diagnostic>> {
+    let mut globals : BTreeMap<String, Global> = BTreeMap::new();
     let mut functions = BTreeMap::new();
+    let mut diagnostics = Vec::new();
+
+    let program = program.next().unwrap();
+    let span = Span::of(&program);
+    let pairs = program.into_inner();
+
+    // constants in order, each may use the ones before it
+    let mut constants = BTreeMap::new();
+    for pair in pairs.clone().filter(|p| p.as_rule() == Rule::constant) {
+        match parse_constant(pair, &constants) {
+            Ok((name, _, span)) if constants.contains_key(&name) => {
+                diagnostics.push(Diagnostic::new(span, format!("'{}' is already defined", name)));
+            },
+            Ok((name, value, _)) => { constants.insert(name, value); },
+            Err(d) => diagnostics.push(d),
+        }
+    }
+
+    // then globals, whose initialisers may use constants, before functions so those declared
+    // ahead of a global still see it
+    let mut address = 0;
+    for pair in pairs.clone().filter(|p| p.as_rule() == Rule::global) {
+        match parse_global(pair, address, &constants) {
+            Ok((name, g)) if globals.contains_key(&name) => {
+                diagnostics.push(Diagnostic::new(g.span, format!("global '{}' is already defined", name)));
+            },
+            Ok((name, g)) if constants.contains_key(&name) => {
+                diagnostics.push(Diagnostic::new(g.span, format!("'{}' is already defined", name)));
+            },
+            Ok((name, g)) => {
+                address += g.size();
+                globals.insert(name, g);
+            },
+            Err(d) => diagnostics.push(d),
+        }
+    }
 
-    let mut program = ProgramParser::parse(Rule::program, &input).unwrap();
-    let pairs = program.next().unwrap().into_inner();
     for pair in pairs {
         match pair.as_rule() {
             Rule::function => {
-                let f = Function::parse(pair);
-                functions.insert(f.name.clone(), f);
+                match Function::parse(pair, &globals, &constants) {
+                    Ok(f) if functions.contains_key(&f.name) => {
+                        diagnostics.push(Diagnostic::new(f.span, format!("function '{}' is already defined", f.name)));
+                    },
+                    Ok(mut f) => {
+                        f.fold(&constants);
+                        functions.insert(f.name.clone(), f);
+                    },
+                    Err(d) => diagnostics.push(d),
+                }
             },
-            Rule::EOI => { },
+            Rule::global | Rule::constant | Rule::EOI => { },
             _ => {
                 panic!("Unexpected rule: {:?}", pair);
             }
         }
     }
 
-    let main = functions.get("main");
-    if main.is_none() {
-        println!("main not found!");
-        return Err(std::io::Error::from(ErrorKind::NotFound));
+    let program = Program { span, globals, constants, functions };
+
+    // checking a partially parsed program would only report knock-on errors
+    if diagnostics.is_empty() {
+        if !program.functions.contains_key("main") {
+            diagnostics.push(Diagnostic::new(program.span, "no main function"));
+        }
+        for f in program.functions.values() {
+            diagnostics.extend(f.check(&program));
+        }
+    }
+
+    if diagnostics.is_empty() {
+        for f in program.functions.values() {
+            diagnostics.extend(f.type_check(&program));
+        }
+    }
+
+    if diagnostics.is_empty() {
+        Ok(program)
+    } else {
+        diagnostics.sort_by_key(|d| d.span.start);
+        Err(diagnostics)
+    }
+}
+
+// the whole program: startup, which calls main and halts, then every function and the
+// runtime routines they use
+fn generate(program: &Program, main: &Function, bounds_check: bool) -> Vec<Line> {
+    // the frame pointer only needs to be kept up to date if some function reads it
+    let mut functions : Vec<FunctionContext> = program.functions.values().map(|f| f.emit(program, bounds_check, false)).collect();
+    let frame_pointer = functions.iter().any(|f| f.uses_frame_pointer);
+    if frame_pointer {
+        functions = program.functions.values().map(|f| f.emit(program, bounds_check, true)).collect();
+    }
+
+    let mut lines = vec![Line::Comment("keep the stack below the frame pointer and the I/O page".to_owned())];
+    let mut reserved = 256 - STACK_TOP;
+    while reserved > 0 {
+        let n = std::cmp::min(reserved, 7);
+        lines.push(Line::Instruction(Instruction::Alloc(StackOffset::new(n as u8))));
+        reserved -= n;
+    }
+    lines.extend(program.initialise());
+    if frame_pointer {
+        // main's return address goes below its result
+        lines.push(Line::Comment("initialise the frame pointer".to_owned()));
+        lines.extend(load_constant(FRAME_POINTER, false).into_iter().map(Line::Instruction));
+        lines.push(Line::Instruction(Instruction::StoreAddr));
+        lines.extend(load_constant((STACK_TOP - main.returns.size() - 1) as u8, false).into_iter().map(Line::Instruction));
+        lines.push(Line::Instruction(Instruction::StoreMem));
+    }
+    lines.push(Line::Comment("call main".to_owned()));
+    for _ in 0..main.returns.size() {
+        lines.push(Line::Instruction(Instruction::WithPush(PushableInstruction::Not(StackOffset::top()))));
+    }
+    lines.push(Line::parse("call :main".to_owned()).unwrap());
+    if main.returns.is_wide() {
+        // a 16-bit result is left with its high byte in ADDR
+        lines.push(Line::Instruction(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::new(1)))));
+        lines.push(Line::Instruction(Instruction::StoreAddr));
+    }
+    lines.push(Line::Instruction(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::top()))));
+    lines.push(Line::parse("halt".to_owned()).unwrap());
+
+    for (f, ctxt) in program.functions.values().zip(functions) {
+        lines.push(Line::Comment(format!("{:?}", f)));
+        lines.extend(ctxt.lines);
+    }
+
+    runtime::link(&mut lines);
+    lines
+}
+
+// a program too big for the ROM is reported against the source, since the assembler's error
+// would point at a line of generated code
+fn assemble_program(program: &Program, lines: Vec<Line>) -> Result<Vec<Instruction>, Vec<Diagnostic>> {
+    let size : usize = lines.iter().map(Line::get_size).sum();
+    if size > 256 {
+        return Err(vec![Diagnostic::new(program.span, format!("program is {} bytes; the ROM holds 256", size))]);
     }
+    assemble("<generated>", lines).map_err(|errors| errors.iter()
+        .map(|e| Diagnostic::new(program.span, format!("generated code does not assemble: {}", e)))
+        .collect())
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let trace = std::env::args().any(|a| a == "--trace");
+    let bounds_check = std::env::args().any(|a| a == "--bounds-check");
+
+    let path = std::env::args().skip(1).find(|a| !a.starts_with("--"));
+    // GETC reads the --input file, or stdin when it doesn't hold the source
+    let args : Vec<String> = std::env::args().collect();
+    let console = Console::from_args(&args, path.is_none())?;
+
+    let input = match &path {
+        Some(path) => fs::read_to_string(path)?,
+        None => {
+            let mut s = String::new();
+            let stdin = io::stdin();
+            stdin.lock().read_to_string(&mut s)?;
+            s
+        }
+    };
+
+    let path = path.unwrap_or_else(|| "<stdin>".to_owned());
 
-    let mut program = Vec::new();
+    let program = match ProgramParser::parse(Rule::program, &input) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("error{}", e.with_path(&path));
+            // the diagnostics are the whole report; an Err from main would add a Debug line
+            std::process::exit(1);
+        }
+    };
+
+    let parsed = match parse_program(program) {
+        Ok(program) => program,
+        Err(diagnostics) => {
+            diagnostics::report(&path, &input, &diagnostics);
+            std::process::exit(1);
+        }
+    };
 
-    program.push(Line::Comment(format!("call main")));
-    program.push(Line::Instruction(Instruction::WithPush(PushableInstruction::Not(StackOffset::top()))));
-    program.push(Line::parse(format!("call :main")));
-    program.push(Line::Instruction(Instruction::WithoutPush(PushableInstruction::LoadFromStack(StackOffset::top()))));
-    program.push(Line::parse(format!("halt")));
+    let program = generate(&parsed, &parsed.functions["main"], bounds_check);
 
-    for f in &functions {
-        program.push(Line::Comment(format!("{:?}", &f.1)));
-        let f = f.1.emit();
-        for l in f.lines {
-            program.push(l);
+    // emit assembler source (e.g. for the debugger) instead of a ROM
+    if std::env::args().any(|a| a == "--asm") {
+        for l in &program {
+            println!("{}", l);
         }
+        return Ok(());
     }
 
-    let rom = assemble(program);
+    let rom = match assemble_program(&parsed, program) {
+        Ok(rom) => rom,
+        Err(diagnostics) => {
+            diagnostics::report(&path, &input, &diagnostics);
+            std::process::exit(1);
+        }
+    };
 
-    simulate(&rom, 10000000);
+    let mut peripherals : Vec<Box<dyn Peripheral>> = vec![Box::new(console)];
+    let simulated = configure(std::env::args()).and_then(|configured| {
+        peripherals.extend(configured);
+        simulate(&encode_rom(&rom), 10000000, trace, peripherals)
+    });
+    if let Err(e) = simulated {
+        eprintln!("error: {}", e);
+        return Err(std::io::Error::from(ErrorKind::InvalidInput));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    // console output that can still be read once the machine owns the console
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // compiles source and runs it to the end, returning the machine and what it printed
+    fn run_with_input(source: &str, input: &'static [u8]) -> (Machine, String) {
+        let pairs = ProgramParser::parse(Rule::program, source).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+        let program = parse_program(pairs).unwrap_or_else(|d| panic!("{:?}\n{}", d, source));
+        let lines = generate(&program, &program.functions["main"], false);
+        let rom = assemble_program(&program, lines).unwrap_or_else(|d| panic!("{:?}\n{}", d, source));
+
+        let output = Output::default();
+        let mut machine = Machine::new(&encode_rom(&rom));
+        machine.attach(Box::new(Console::new(Box::new(input), Box::new(output.clone())))).unwrap();
+        assert_eq!(StopReason::Halted, machine.run(10_000_000), "{}", source);
+        let printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+        (machine, printed)
+    }
+
+    fn run(source: &str) -> u8 {
+        run_with_input(source, b"").0.reg(Reg::ACC)
+    }
+
+    // main returns a 16-bit result with its high byte in ADDR
+    fn run16(source: &str) -> u16 {
+        let (machine, _) = run_with_input(source, b"");
+        u16::from_le_bytes([machine.reg(Reg::ACC), machine.reg(Reg::ADDR)])
+    }
+
+    #[test]
+    fn examples() {
+        assert_eq!(233, run(include_str!("../../fib.j")));
+        assert_eq!(120, run(include_str!("../../fac.j")));
+        assert_eq!(255, run(include_str!("../../mem.j")));
+        assert_eq!(46368, run16(include_str!("../../fib16.j")));
+        assert_eq!(3500, run16(include_str!("../../sum.j")));
+        assert_eq!(91, run(include_str!("../../table.j")));
+        assert_eq!("hello, world\n", run_with_input(include_str!("../../hello.j"), b"").1);
+    }
+
+    #[test]
+    fn console_echoes_input() {
+        let source = "
+            FUNCTION main() {
+                LOCAL c: u8;
+                ASSIGN n := 0;
+                ASSIGN c := GETC;
+                WHILE c != 0 {
+                    PUTC c;
+                    ASSIGN n := n + 1;
+                    ASSIGN c := GETC;
+                }
+                RETURN n;
+            }";
+        let (machine, printed) = run_with_input(source, b"mark3");
+        assert_eq!(5, machine.reg(Reg::ACC));
+        assert_eq!("mark3", printed);
+    }
+
+    // runs body with a and b set, returning r; r starts at 0
+    fn compare(ty: &str, a: i32, b: i32, body: &str) -> u8 {
+        run(&format!("
+            FUNCTION main() {{
+                LOCAL a: {ty};
+                LOCAL b: {ty};
+                LOCAL t: bool;
+                LOCAL r: u8;
+                ASSIGN a := {a};
+                ASSIGN b := {b};
+                ASSIGN r := 0;
+                {body}
+                RETURN r;
+            }}", ty = ty, a = a, b = b, body = body))
+    }
+
+    #[test]
+    fn comparisons_carry_and_respect_signedness() {
+        let values : &[(&str, &[i32])] = &[
+            ("u8", &[0, 1, 0x7f, 0x80, 0xff]),
+            ("i8", &[-0x80, -1, 0, 1, 0x7f]),
+            ("u16", &[0, 1, 0xff, 0x100, 0x7fff, 0x8000, 0xfffe, 0xffff]),
+            ("i16", &[-0x8000, -0x100, -1, 0, 1, 0xff, 0x100, 0x7fff]),
+        ];
+        // a bit for each comparison, both branched on and stored as a value
+        let branched = "
+            IF a < b { ASSIGN r := r + 1; }
+            IF a <= b { ASSIGN r := r + 2; }
+            IF a > b { ASSIGN r := r + 4; }
+            IF a >= b { ASSIGN r := r + 8; }
+            IF a == b { ASSIGN r := r + 16; }
+            IF a != b { ASSIGN r := r + 32; }";
+        let stored = "
+            ASSIGN t := a > b;
+            IF t { ASSIGN r := r + 1; }
+            ASSIGN t := a < b;
+            IF t { ASSIGN r := r + 2; }";
+        for (ty, values) in values {
+            for &a in *values {
+                for &b in *values {
+                    let expected = (a < b) as u8 + (a <= b) as u8 * 2 + (a > b) as u8 * 4 + (a >= b) as u8 * 8
+                        + (a == b) as u8 * 16 + (a != b) as u8 * 32;
+                    assert_eq!(expected, compare(ty, a, b, branched), "{} {} {}", ty, a, b);
+                    assert_eq!((a > b) as u8 + (a < b) as u8 * 2, compare(ty, a, b, stored), "{} {} {}", ty, a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn division_and_remainder() {
+        for &a in &[0u8, 1, 7, 100, 200, 255] {
+            for &b in &[0u8, 1, 2, 3, 7, 16, 255] {
+                let source = format!("
+                    GLOBAL q;
+                    FUNCTION main() {{
+                        LOCAL a: u8;
+                        LOCAL b: u8;
+                        ASSIGN a := {};
+                        ASSIGN b := {};
+                        ASSIGN q := a / b;
+                        RETURN a % b;
+                    }}", a, b);
+                let (machine, _) = run_with_input(&source, b"");
+                // dividing by zero gives a quotient of ff and a remainder of a
+                let (q, r) = a.checked_div(b).map_or((0xff, a), |q| (q, a % b));
+                assert_eq!((q, r), (machine.mem(0), machine.reg(Reg::ACC)), "{} / {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn shifts() {
+        for &a in &[1u32, 0x81, 0xff] {
+            for n in 0..=9 {
+                let shift = |op: &str| run(&format!("
+                    FUNCTION main() {{
+                        LOCAL a: u8;
+                        LOCAL n: u8;
+                        ASSIGN a := {};
+                        ASSIGN n := {};
+                        RETURN a {} n;
+                    }}", a, n, op));
+                assert_eq!((a << n) as u8, shift("<<"), "{} << {}", a, n);
+                assert_eq!((a >> n) as u8, shift(">>"), "{} >> {}", a, n);
+            }
+        }
+    }
+
+    #[test]
+    fn arithmetic_16_bit_carries() {
+        let values = [0u16, 1, 2, 0xff, 0x100, 0x1234, 0x7fff, 0x8000, 0xffff];
+        for &a in &values {
+            for &b in &values {
+                let op = |op: &str| run16(&format!("
+                    FUNCTION main() -> u16 {{
+                        LOCAL a: u16;
+                        LOCAL b: u16;
+                        ASSIGN a := {};
+                        ASSIGN b := {};
+                        RETURN a {} b;
+                    }}", a, b, op));
+                assert_eq!(a.wrapping_add(b), op("+"), "{} + {}", a, b);
+                assert_eq!(a.wrapping_sub(b), op("-"), "{} - {}", a, b);
+                assert_eq!(a.wrapping_mul(b), op("*"), "{} * {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn comparisons_take_signedness_from_their_operands() {
+        // s is an ordinary identifier, not a suffix on <
+        assert_eq!(1, run("FUNCTION main() { ASSIGN i := 3; ASSIGN s := 5; RETURN i<s; }"));
+        assert_eq!(0, run("FUNCTION main() { LOCAL x: i8; ASSIGN x := -1; RETURN x > 0; }"));
+        assert_eq!(1, run("FUNCTION main() { LOCAL x: u8; ASSIGN x := 255; RETURN x > 0; }"));
+    }
+
+    #[test]
+    fn deep_frames_fit_in_the_rom() {
+        let source = "
+            FUNCTION main() {
+                LOCAL a[100];
+                ASSIGN i := 99;
+                ASSIGN a[i] := 7;
+                ASSIGN i := i - 1;
+                RETURN a[i + 1];
+            }";
+        assert_eq!(7, run(source));
+
+        // arguments and the result sit beyond the locals, far from SP
+        let source = "
+            FUNCTION f(a, b, w: u16, c) -> u16 {
+                LOCAL big[40];
+                ASSIGN big[39] := a;
+                ASSIGN w := w | (big[39] + b + c) as u16;
+                RETURN w;
+            }
+            FUNCTION main() -> u16 {
+                RETURN f(10, 20, 768, 3);
+            }";
+        assert_eq!(768 + 33, run16(source));
+    }
+
+    #[test]
+    fn complements_of_literals_fold() {
+        let source = "
+            CONST m := ~15;
+            CONST w := ~0 as u16;
+            GLOBAL t := [~1, m, ~m];
+            FUNCTION main() -> u16 {
+                LOCAL x: u8;
+                ASSIGN x := ~5 & m;
+                IF x != 240 { RETURN 1; }
+                IF t[0] != 254 { RETURN 2; }
+                IF t[2] != 15 { RETURN 3; }
+                RETURN w;
+            }";
+        assert_eq!(0xffff, run16(source));
+    }
+
+    #[test]
+    fn complemented_literals_take_the_expected_width() {
+        assert_eq!(0xfffa, run16("FUNCTION main() -> u16 { LOCAL w: u16; ASSIGN w := ~5; RETURN w; }"));
+        assert_eq!(0xfff9, run16("FUNCTION main() -> u16 { LOCAL w: u16; ASSIGN w := 65530; RETURN w + ~0; }"));
+        assert_eq!(0xfa, run("FUNCTION main() { LOCAL x: u8; ASSIGN x := ~5; RETURN x; }"));
+    }
+
+
+    #[test]
+    fn long_operator_chains_compile() {
+        let sum = vec!["a"; 40].join(" + ");
+        let source = format!("FUNCTION main() {{ LOCAL a: u8; ASSIGN a := 1; RETURN {}; }}", sum);
+        assert_eq!(40, run(&source));
+    }
+
+
+    // compiles source, returning its diagnostics as they would be printed
+    fn errors(source: &str) -> Vec<String> {
+        let pairs = ProgramParser::parse(Rule::program, source).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+        let compiled = parse_program(pairs).and_then(|program| {
+            let lines = generate(&program, &program.functions["main"], false);
+            assemble_program(&program, lines)
+        });
+        compiled.err().unwrap_or_default().iter().map(|d| d.render("t.j", source)).collect()
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_source() {
+        assert_eq!(vec![
+            " --> t.j:1:26\n  |\n1 | FUNCTION main() { RETURN x; }\n  |                          ^\n  |\n  = undefined variable 'x'",
+        ], errors("FUNCTION main() { RETURN x; }"));
+        assert_eq!(vec![
+            " --> t.j:1:1\n  |\n1 | GLOBAL x;\n  | ^-------^\n  |\n  = no main function",
+        ], errors("GLOBAL x;"));
+    }
+
+    #[test]
+    fn a_program_too_big_for_the_rom_is_one_diagnostic() {
+        let source = format!("FUNCTION main() {{\n{}    RETURN 0;\n}}\n", "    PUTC 65;\n".repeat(60));
+        let errors = errors(&source);
+        assert_eq!(1, errors.len(), "{:?}", errors);
+        assert!(errors[0].contains("--> t.j:1:1\n"), "{}", errors[0]);
+        assert!(errors[0].ends_with("= program is 437 bytes; the ROM holds 256"), "{}", errors[0]);
+    }
+
+
+    #[test]
+    fn else_if_chains_take_exactly_one_branch() {
+        for &(x, expected) in &[(0, 1), (9, 1), (10, 2), (19, 2), (20, 3), (21, 4), (255, 4)] {
+            let source = format!("
+                FUNCTION main() {{
+                    LOCAL x: u8;
+                    ASSIGN x := {};
+                    ASSIGN r := 0;
+                    IF x < 10 {{
+                        ASSIGN r := r + 1;
+                    }} ELSE IF x < 20 {{
+                        ASSIGN r := r + 2;
+                    }} ELSE IF x == 20 {{
+                        ASSIGN r := r + 3;
+                    }} ELSE {{
+                        ASSIGN r := r + 4;
+                    }}
+                    IF x {{ ASSIGN r := r + 10; }} ELSE {{ ASSIGN r := r + 20; }}
+                    RETURN r;
+                }}", x);
+            let tail = if x == 0 { 20 } else { 10 };
+            assert_eq!(expected + tail, run(&source), "x = {}", x);
+        }
+    }
+
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        // f prints x, so the output shows whether the right operand ran
+        let logical = |expression: &str| run_with_input(&format!("
+            FUNCTION f() {{
+                PUTC 120;
+                RETURN 1;
+            }}
+            FUNCTION main() {{
+                ASSIGN z := 0;
+                ASSIGN o := 1;
+                RETURN {};
+            }}", expression), b"");
+        for (expression, result, printed) in &[
+            ("z && f()", 0, ""),
+            ("o && f()", 1, "x"),
+            ("o || f()", 1, ""),
+            ("z || f()", 1, "x"),
+            ("z && f() || o", 1, ""),
+            ("o || f() && f()", 1, ""),
+        ] {
+            let (machine, output) = logical(expression);
+            assert_eq!((*result, *printed), (machine.reg(Reg::ACC), output.as_str()), "{}", expression);
+        }
+
+        // a guard keeps the division from running
+        let source = "
+            FUNCTION main() {
+                ASSIGN z := 0;
+                IF z != 0 && 10 / z > 1 { PUTC 120; }
+                IF z == 0 || f(10 / z) { PUTC 121; }
+                RETURN 0;
+            }
+            FUNCTION f(x) {
+                PUTC 122;
+                RETURN 1;
+            }";
+        assert_eq!("y", run_with_input(source, b"").1);
+    }
+
+    #[test]
+    fn bitwise_and_logical_operators_on_each_type() {
+        let values : &[(&str, &[i32])] = &[
+            ("u8", &[0, 1, 0x5a, 0xff]),
+            ("i8", &[-0x80, -1, 0, 0x5a]),
+            ("u16", &[0, 1, 0x5aa5, 0xffff]),
+        ];
+        for (ty, values) in values {
+            let mask = if *ty == "u16" { 0xffff } else { 0xff };
+            let eval = |a: i32, b: i32, expression: &str| {
+                let source = format!("
+                    FUNCTION main() -> u16 {{
+                        LOCAL a: {ty};
+                        LOCAL b: {ty};
+                        ASSIGN a := {a};
+                        ASSIGN b := {b};
+                        RETURN ({expression}) as u16;
+                    }}", ty = ty, a = a, b = b, expression = expression);
+                // a cast from a signed type sign extends, so compare the operand's width only
+                run16(&source) as i32 & mask
+            };
+            for &a in *values {
+                for &b in *values {
+                    assert_eq!(a & b & mask, eval(a, b, "a & b"), "{} {} & {}", ty, a, b);
+                    assert_eq!((a | b) & mask, eval(a, b, "a | b"), "{} {} | {}", ty, a, b);
+                    assert_eq!((a ^ b) & mask, eval(a, b, "a ^ b"), "{} {} ^ {}", ty, a, b);
+                }
+                assert_eq!(!a & mask, eval(a, 0, "~a"), "{} ~{}", ty, a);
+                assert_eq!((a == 0) as i32, eval(a, 0, "!a"), "{} !{}", ty, a);
+                assert_eq!((a == 0) as i32, eval(a, 0, "!!!a"), "{} !!!{}", ty, a);
+            }
+        }
+    }
+
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        let eval = |expression: &str| run16(&format!("
+            FUNCTION main() -> u16 {{
+                LOCAL a: u8;
+                LOCAL b: u8;
+                LOCAL c: u8;
+                ASSIGN a := 2;
+                ASSIGN b := 3;
+                ASSIGN c := 4;
+                RETURN ({}) as u16;
+            }}", expression));
+        for (expression, expected) in &[
+            // * and / before + and -
+            ("a + b * c", 14),
+            ("a * b + c", 10),
+            ("c - b * a", 254),
+            ("c + c / a", 6),
+            // - and / associate to the left
+            ("c - b - a", 255),
+            ("c * c / a / a", 4),
+            ("c - a + b", 5),
+            // comparisons before && and ||
+            ("a == a && b == b", 1),
+            ("a == b || c == c", 1),
+            ("a < b && b < c", 1),
+            ("c > b || a > b && b > c", 1),
+            // && before ||
+            ("a == b && b == b || c == c", 1),
+            ("c == c || a == b && b == b", 1),
+            // unary operators before binary ones
+            ("-a + b", 1),
+            ("b - -a", 5),
+            ("~a & c", 4),
+            ("!a || b == b", 1),
+        ] {
+            assert_eq!(*expected, eval(expression), "{}", expression);
+        }
+
+        // as binds to its operand, not to the whole operation
+        let source = "
+            FUNCTION main() -> u16 {
+                LOCAL a: u8;
+                LOCAL b: u8;
+                ASSIGN a := 200;
+                ASSIGN b := 100;
+                RETURN a as u16 + b as u16;
+            }";
+        assert_eq!(300, run16(source));
+    }
+
+
+    // the message of each diagnostic, without the source it points at
+    fn messages(source: &str) -> Vec<String> {
+        errors(source).iter().map(|e| e.rsplit("  = ").next().unwrap().to_owned()).collect()
+    }
+
+    #[test]
+    fn arrays_and_globals_are_limited_by_ram() {
+        let limit = "array length must be between 1 and 239";
+        for source in &[
+            "GLOBAL a[0]; FUNCTION main() { RETURN 0; }",
+            "GLOBAL a[240]; FUNCTION main() { RETURN 0; }",
+            "GLOBAL a := []; FUNCTION main() { RETURN 0; }",
+            "FUNCTION main() { LOCAL a[0]; RETURN 0; }",
+            "FUNCTION main() { LOCAL a[240]; RETURN 0; }",
+        ] {
+            assert_eq!(vec![limit], messages(source), "{}", source);
+        }
+        let long = format!("GLOBAL s := \"{}\"; FUNCTION main() {{ RETURN 0; }}", "x".repeat(239));
+        assert_eq!(vec![limit], messages(&long));
+
+        // together the globals must fit too, and then leave room for the stack
+        assert_eq!(vec!["global 'b' does not fit in memory: the globals need 240 bytes but RAM holds 239"],
+            messages("GLOBAL a[200]; GLOBAL b[40]; FUNCTION main() { RETURN 0; }"));
+        assert_eq!(vec!["main needs a 2 byte stack frame but the stack is only 0 bytes"],
+            messages("GLOBAL a[200]; GLOBAL b[39]; FUNCTION main() { RETURN 0; }"));
+        assert_eq!(vec!["main needs a 232 byte stack frame but the stack is only 229 bytes"],
+            messages("GLOBAL a[10]; FUNCTION main() { LOCAL b[230]; RETURN 0; }"));
+        assert_eq!(5, run("GLOBAL a[200]; GLOBAL b[30]; FUNCTION main() { ASSIGN b[29] := 5; RETURN b[29]; }"));
+    }
+
+
+    #[test]
+    fn folded_values_are_range_checked_and_casts_wrap() {
+        // arithmetic on literals is done exactly at compile time, so a result that doesn't fit
+        // is an error like the literal would be, rather than wrapping at run time
+        assert_eq!(vec!["literal -1 is out of range for u8"],
+            messages("FUNCTION main() { ASSIGN x := 0 - 1; RETURN x; }"));
+        assert_eq!(vec!["literal 300 is out of range for u8"],
+            messages("FUNCTION main() { ASSIGN x := 200 + 100; RETURN x; }"));
+        assert_eq!(vec!["literal 300 is out of range for u8"],
+            messages("FUNCTION main() { ASSIGN x := 1; RETURN x + (200 + 100); }"));
+
+        // wider types take the exact value, and a cast asks for the wrapped one
+        assert_eq!(300, run16("FUNCTION main() -> u16 { LOCAL x: u16; ASSIGN x := 200 + 100; RETURN x; }"));
+        assert_eq!(255, run("FUNCTION main() { ASSIGN x := (0 - 1) as u8; RETURN x; }"));
+        assert_eq!(44, run("FUNCTION main() { ASSIGN x := (200 + 100) as u8; RETURN x; }"));
+    }
+
+
+    #[test]
+    fn initialisers_store_only_bytes_that_are_not_zero() {
+        let source = "
+            GLOBAL s[8] := \"hi\";
+            GLOBAL t := [0, 3, 0, 0, 7];
+            GLOBAL z := [0, 0];
+            FUNCTION main() {
+                IF s[1] != 105 { RETURN 1; }
+                IF s[2] != 0 { RETURN 2; }
+                IF s[7] != 0 { RETURN 3; }
+                IF t[1] != 3 { RETURN 4; }
+                IF t[2] != 0 { RETURN 5; }
+                IF t[4] != 7 { RETURN 6; }
+                IF z[1] != 0 { RETURN 7; }
+                RETURN 0;
+            }";
+        assert_eq!(0, run(source));
+
+        let program = parse_program(ProgramParser::parse(Rule::program, source).unwrap()).unwrap();
+        let stores = program.initialise().iter()
+            .filter(|l| matches!(l, Line::Instruction(Instruction::StoreMem)))
+            .count();
+        assert_eq!(4, stores);
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::BTreeSet;
+
+use common::*;
+
+// helpers the compiler calls for operations mark3 has no instruction for
+struct Routine {
+    file: &'static str,
+    labels: &'static [&'static str],
+    source: &'static str,
+}
+
+const ROUTINES: &[Routine] = &[
+    Routine { file: "runtime/divmod.asm", labels: &[":__div8", ":__mod8"], source: include_str!("runtime/divmod.asm") },
+    Routine { file: "runtime/shl.asm", labels: &[":__shl8"], source: include_str!("runtime/shl.asm") },
+    Routine { file: "runtime/shr.asm", labels: &[":__shr8"], source: include_str!("runtime/shr.asm") },
+    Routine { file: "runtime/mul16.asm", labels: &[":__mul16"], source: include_str!("runtime/mul16.asm") },
+    Routine { file: "runtime/adc8.asm", labels: &[":__adc8"], source: include_str!("runtime/adc8.asm") },
+    Routine { file: "runtime/bounds.asm", labels: &[":__bounds_fail"], source: include_str!("runtime/bounds.asm") },
+];
+
+fn referenced_labels(lines: &[Line], labels: &mut BTreeSet<String>) {
+    let mut add = |i: &Instruction| match i {
+        Instruction::Jmp(Target::Label(l))
+        | Instruction::Jz(Target::Label(l))
+        | Instruction::Jnz(Target::Label(l))
+        | Instruction::WithPush(PushableInstruction::LoadLo(Target::Label(l)))
+        | Instruction::WithoutPush(PushableInstruction::LoadLo(Target::Label(l)))
+        | Instruction::WithPush(PushableInstruction::LoadHi(Target::Label(l)))
+        | Instruction::WithoutPush(PushableInstruction::LoadHi(Target::Label(l))) => {
+            labels.insert(l.clone());
+        },
+        _ => {}
+    };
+
+    for line in lines {
+        match line {
+            Line::Instruction(i) => add(i),
+            Line::Macro(_, instructions) => instructions.iter().for_each(&mut add),
+            _ => {}
+        }
+    }
+}
+
+// appends the routines the program (or an already linked routine) refers to
+pub fn link(program: &mut Vec<Line>) {
+    let mut linked = BTreeSet::new();
+    loop {
+        let mut labels = BTreeSet::new();
+        referenced_labels(program, &mut labels);
+
+        let needed = ROUTINES.iter().enumerate().find(|(n, r)| {
+            !linked.contains(n) && r.labels.iter().any(|l| labels.contains(*l))
+        });
+
+        match needed {
+            Some((n, routine)) => {
+                linked.insert(n);
+                program.push(Line::Comment(format!("runtime: {}", routine.file)));
+                program.extend(parse_lines(routine.file, routine.source).expect("runtime does not parse"));
+            },
+            None => break,
+        }
+    }
+}
@@ -0,0 +1,35 @@
+# __adc8(x, y, c): x + y + c for bytes x and y and a carry c of 0 or 1
+# mark3 can't read the carry an ADD sets, so it is worked out from the top bits: it is the top
+# bit of (x & y) | ((x | y) & ~sum)
+#
+# on entry: sp+0 return address, sp+1 c, sp+2 y, sp+3 x
+# on return: sp+0 the carry out (0 or 1), sp+1 the sum, sp+2 x
+
+:__adc8
+loadfromstack 3
+add 2
+add 1 push              # sum
+
+# sp+0 sum, sp+1 return address, sp+2 c, sp+3 y, sp+4 x
+
+not 0
+storetostack 2
+loadfromstack 3
+or 4
+and 2
+storetostack 2
+loadfromstack 3
+and 4
+or 2
+storetostack 2          # the carry is the top bit
+loadfromstack 0
+storetostack 3          # y <- sum
+loadlo 0
+loadhi 8
+and 2
+jz :__adc8_ret          # ACC is 0
+loadlo 1
+:__adc8_ret
+storetostack 2          # c <- carry
+discard 1
+ret
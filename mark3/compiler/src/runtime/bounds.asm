@@ -0,0 +1,5 @@
+# __bounds_fail: reached when an array index is out of range (--bounds-check)
+# stops the machine on an illegal instruction so the failure can't be mistaken for a result
+
+:__bounds_fail
+?? 3f
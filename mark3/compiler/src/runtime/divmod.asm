@@ -0,0 +1,65 @@
+# __div8(a, b) and __mod8(a, b): unsigned restoring division
+# dividing by zero gives a quotient of ff and a remainder of a
+#
+# on entry: sp+0 return address, sp+1 b, sp+2 a, sp+3 result
+
+:__div8
+loadlo 0 push           # selector: 0 returns the quotient
+jmp :__divmod8
+:__mod8
+loadlo 1 push           # selector: 1 returns the remainder
+:__divmod8
+loadlo 0 push           # r
+loadlo 8 push           # count: -8 up to 0
+
+# sp+0 count, sp+1 r, sp+2 selector, sp+3 return address, sp+4 b
+# sp+5 a; quotient bits are shifted in from the bottom as a shifts out
+# sp+6 result
+
+:__divmod8_loop
+loadlo 0
+loadhi 8
+and 1 push              # the ninth bit of 2r
+loadlo 0
+loadhi 8
+and 6                   # top bit of a
+jz :__divmod8_shift
+loadlo 1
+:__divmod8_shift
+add 2
+add 2
+storetostack 2          # r = 2r + bit
+loadfromstack 6
+add 6
+storetostack 6          # a += a
+loadfromstack 2 push
+not 6 push
+loadlo 1 push
+call :__adc8            # r + ~b + 1 == r - b, and it carries exactly when r >= b
+
+# sp+0 carry, sp+1 r - b, sp+2 r, sp+3 ninth bit, sp+4 count, sp+5 r
+
+loadfromstack 0
+or 3
+jz :__divmod8_next      # r < b, and ACC is 0
+loadfromstack 1
+storetostack 5          # r -= b
+loadlo 1                # quotient bit
+:__divmod8_next
+discard 4
+or 5
+storetostack 5
+loadlo 1
+add 0
+storetostack 0
+jnz :__divmod8_loop
+
+loadfromstack 5
+storetostack 6
+loadfromstack 2
+jz :__divmod8_ret
+loadfromstack 1
+storetostack 6
+:__divmod8_ret
+discard 3
+ret
@@ -0,0 +1,76 @@
+# __mul16(a, b): the low 16 bits of a * b
+# 16-bit values are little-endian: the low byte is in the lower slot
+#
+# on entry: sp+0 return address, sp+1 b lo, sp+2 b hi, sp+3 a lo, sp+4 a hi
+# sp+5 result lo, sp+6 result hi
+
+:__mul16
+loadlo 0
+loadhi f push           # count: -16 up to 0
+loadlo 0 push
+loadlo 0 push           # r = 0
+
+# sp+0 r lo, sp+1 r hi, sp+2 count, sp+3 return address, sp+4 b lo, sp+5 b hi
+# sp+6 a lo, sp+7 a hi; a is shifted out from the top, one bit per round
+
+:__mul16_loop
+loadlo 0
+loadhi 8
+and 0                   # top bit of r lo
+jz :__mul16_double
+loadlo 1
+:__mul16_double
+add 1
+add 1
+storetostack 1
+loadfromstack 0
+add 0
+storetostack 0          # r += r
+loadlo 0
+loadhi 8
+and 7                   # top bit of a
+jz :__mul16_next
+loadfromstack 5
+add 1
+storetostack 1
+loadfromstack 0 push
+loadfromstack 5 push
+loadlo 0 push
+call :__adc8
+loadfromstack 0
+add 4
+storetostack 4
+loadfromstack 1
+storetostack 3          # r += b
+discard 3
+:__mul16_next
+loadlo 0
+loadhi 8
+and 6                   # top bit of a lo
+jz :__mul16_shift
+loadlo 1
+:__mul16_shift
+add 7
+add 7
+storetostack 7
+loadfromstack 6
+add 6
+storetostack 6          # a += a
+loadlo 1
+add 2
+storetostack 2
+jnz :__mul16_loop
+
+# a is spent; move r through its slots into the result
+
+loadfromstack 0
+storetostack 6
+loadfromstack 1
+storetostack 7
+discard 2
+loadfromstack 4
+storetostack 6
+loadfromstack 5
+storetostack 7
+discard 1
+ret
@@ -0,0 +1,18 @@
+# __shl8(a, n): a << n
+#
+# on entry: sp+0 return address, sp+1 n, sp+2 a, sp+3 result
+
+:__shl8
+loadfromstack 1
+jz :__shl8_done
+loadlo f
+add 1
+storetostack 1          # n -= 1
+loadfromstack 2
+add 2
+storetostack 2          # a += a
+jmp :__shl8
+:__shl8_done
+loadfromstack 2
+storetostack 3
+ret
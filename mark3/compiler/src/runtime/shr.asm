@@ -0,0 +1,45 @@
+# __shr8(a, n): logical a >> n
+# rotates a left 8 - n times and masks off the n bits that wrapped around
+#
+# on entry: sp+0 return address, sp+1 n, sp+2 a, sp+3 result
+
+:__shr8
+loadlo 8
+and 1
+jnz :__shr8_zero        # n >= 8
+loadlo 8
+add 1
+storetostack 1          # count: n - 8 up to 0
+loadlo f push           # mask: shifted left once per rotation, inverted at the end
+
+# sp+0 mask, sp+1 return address, sp+2 count, sp+3 a, sp+4 result
+
+:__shr8_loop
+loadfromstack 2
+jz :__shr8_done
+loadlo 1
+add 2
+storetostack 2
+loadlo 0
+loadhi 8
+and 3                   # top bit of a
+jz :__shr8_rotate
+loadlo 1
+:__shr8_rotate
+add 3
+add 3
+storetostack 3          # a rotated left by one
+loadfromstack 0
+add 0
+storetostack 0
+jmp :__shr8_loop
+:__shr8_done
+not 0
+and 3
+storetostack 4
+discard 1
+ret
+:__shr8_zero
+loadlo 0
+storetostack 3
+ret
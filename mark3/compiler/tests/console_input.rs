@@ -0,0 +1,47 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// echoes GETC to PUTC until the input runs out
+const ECHO : &str = "
+    FUNCTION main() {
+        LOCAL c: u8;
+        ASSIGN c := GETC;
+        WHILE c != 0 {
+            PUTC c;
+            ASSIGN c := GETC;
+        }
+        RETURN 0;
+    }";
+
+#[test]
+fn getc_reads_the_input_file_when_the_source_is_on_stdin() {
+    let input = std::env::temp_dir().join(format!("compiler-input-{}", std::process::id()));
+    std::fs::write(&input, "mark3").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .arg(format!("--input={}", input.display()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(ECHO.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // the listing comes first; the program's output follows the start of the simulation
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("# begin simulation\nmark3# simulation completed"), "{}", stdout);
+}
+
+#[test]
+fn a_missing_input_file_is_an_error() {
+    let output = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .arg("--input=/nonexistent/input")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("/nonexistent/input"));
+}
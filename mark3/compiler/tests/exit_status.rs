@@ -0,0 +1,39 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// runs the compiler on source given on stdin
+fn compile(source: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(source.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn a_valid_program_runs() {
+    let output = compile("FUNCTION main() { RETURN 3; }");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn errors_go_to_stderr_with_status_1() {
+    let too_big = format!("FUNCTION main() {{ {} RETURN 0; }}", "PUTC 65; ".repeat(60));
+    for (source, message) in &[
+        ("FUNCTION main() { RETURN 3 }", "expected"),
+        ("FUNCTION main() { RETURN x; }", "= undefined variable 'x'"),
+        ("GLOBAL x;", "= no main function"),
+        (too_big.as_str(), "= program is 437 bytes; the ROM holds 256"),
+    ] {
+        let output = compile(source);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(Some(1), output.status.code(), "{}", source);
+        assert!(output.stdout.is_empty(), "{}", source);
+        assert!(stderr.starts_with("error"), "{}", stderr);
+        assert!(stderr.contains(message), "{}", stderr);
+    }
+}
@@ -0,0 +1,284 @@
+use std::io::{self, BufRead, Write};
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+use common::*;
+
+const CONTINUE_LIMIT : usize = 10_000_000;
+
+const HELP : &str = "\
+commands (addresses and values are hex; addresses may also be :labels):
+  s, step [count]        execute count instructions (default 1)
+  c, continue            run until a breakpoint, halt or fault
+  b, break <addr>        set a breakpoint
+  d, delete <addr>       remove a breakpoint
+  breakpoints            list breakpoints
+  r, regs                show registers
+  stack                  show the stack from SP to the top of memory
+  x, mem <addr> [count]  dump memory (default 16 bytes)
+  l, list [count]        disassemble from PC (default 8 instructions)
+  labels                 show the symbol table
+  set <reg> <value>      write a register (acc, addr, flags, sp, pc)
+  poke <addr> <value>    write a memory byte
+  reset                  restart the program
+  q, quit                exit";
+
+struct Debugger {
+    machine: Machine,
+    labels: BTreeMap<String, u8>,
+    breakpoints: BTreeSet<u8>,
+    // the command line, which configures the peripherals again on reset
+    args: Vec<String>,
+}
+
+// a machine with the console, which reads from the --input file if any (commands come from
+// stdin so the program can't share it), and the configured peripherals
+fn machine(rom: &[u8], args: &[String]) -> Result<Machine, String> {
+    let console = Console::from_args(args, true).map_err(|e| e.to_string())?;
+    let mut machine = Machine::new(rom);
+    machine.attach(Box::new(console))?;
+    for p in configure(args.iter().cloned())? {
+        machine.attach(p)?;
+    }
+    Ok(machine)
+}
+
+impl Debugger {
+    fn address(&self, s: &str) -> Result<u8, String> {
+        if s.starts_with(':') {
+            self.labels.get(s).cloned().ok_or_else(|| format!("unknown label {}", s))
+        } else {
+            u8::from_str_radix(s, 16).map_err(|_| format!("invalid address '{}'", s))
+        }
+    }
+
+    fn labels_at(&self, addr: u8) -> Vec<&str> {
+        self.labels.iter()
+            .filter(|(_, a)| **a == addr)
+            .map(|(l, _)| l.as_str())
+            .collect()
+    }
+
+    fn describe(&self, addr: u8) -> String {
+        match self.labels_at(addr).first() {
+            Some(l) => format!("@{:02x} {}", addr, l),
+            None => format!("@{:02x}", addr),
+        }
+    }
+
+    fn show_current(&self) {
+        match self.machine.fetch() {
+            Ok((i, _)) => println!("{}: {}", self.describe(self.machine.reg(Reg::PC)), i),
+            Err(reason) => println!("{}: {:?}", self.describe(self.machine.reg(Reg::PC)), reason),
+        }
+    }
+
+    fn show_stop(&self, reason: StopReason) {
+        match reason {
+            StopReason::Halted => println!("halted after {} cycles, acc = {:02x}",
+                self.machine.cycles(), self.machine.reg(Reg::ACC)),
+            reason => {
+                println!("stopped after {} cycles: {:?}", self.machine.cycles(), reason);
+                self.show_current();
+            }
+        }
+    }
+
+    fn show_regs(&self) {
+        for r in &[Reg::ACC, Reg::ADDR, Reg::FLAGS, Reg::SP, Reg::PC] {
+            print!("{}:{:02x} ", r, self.machine.reg(*r));
+        }
+        println!("cycles:{}", self.machine.cycles());
+    }
+
+    fn show_stack(&self) {
+        // SP starts at 0 and the first push wraps it to ff
+        let sp = self.machine.reg(Reg::SP);
+        if sp == 0 {
+            println!("(empty)");
+            return;
+        }
+        for (offset, value) in self.machine.stack().iter().enumerate() {
+            println!("sp+{:<3x} @{:02x}: {:02x}", offset, sp as usize + offset, value);
+        }
+    }
+
+    fn show_mem(&self, addr: u8, count: usize) {
+        for row in (0..count).step_by(16) {
+            print!("{:02x}:", addr.wrapping_add(row as u8));
+            for i in row..std::cmp::min(row + 16, count) {
+                print!(" {:02x}", self.machine.mem(addr.wrapping_add(i as u8)));
+            }
+            println!();
+        }
+    }
+
+    fn list(&self, count: usize) {
+        let rom = self.machine.rom();
+        let mut pc = self.machine.reg(Reg::PC) as usize;
+        for _ in 0..count {
+            let (i, size) = match rom.get(pc..).and_then(Instruction::decode) {
+                Some(decoded) => decoded,
+                None => break,
+            };
+            for l in self.labels_at(pc as u8) {
+                println!("{}", l);
+            }
+            let marker = if self.breakpoints.contains(&(pc as u8)) { "*" } else { " " };
+            println!("{} @{:02x}: {}", marker, pc, i);
+            pc += size;
+        }
+    }
+
+    fn step(&mut self, count: usize) {
+        for _ in 0..count {
+            if let Some(reason) = self.machine.step() {
+                self.show_stop(reason);
+                return;
+            }
+        }
+        self.show_current();
+    }
+
+    fn cont(&mut self) {
+        let start = self.machine.cycles();
+        loop {
+            if let Some(reason) = self.machine.step() {
+                self.show_stop(reason);
+                return;
+            }
+            if self.breakpoints.contains(&self.machine.reg(Reg::PC)) {
+                println!("breakpoint");
+                self.show_current();
+                return;
+            }
+            if self.machine.cycles() - start == CONTINUE_LIMIT {
+                self.show_stop(StopReason::CycleLimit);
+                return;
+            }
+        }
+    }
+
+    // returns false when the user asked to quit
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let tokens : Vec<&str> = line.split_whitespace().collect();
+        let arg = |n: usize| tokens.get(n).cloned().ok_or_else(|| format!("{} needs more arguments", tokens[0]));
+        let count = |n: usize, default: usize| match tokens.get(n) {
+            Some(c) => usize::from_str(c).map_err(|_| format!("invalid count '{}'", c)),
+            None => Ok(default),
+        };
+        let value = |s: &str| u8::from_str_radix(s, 16).map_err(|_| format!("invalid value '{}'", s));
+
+        match tokens.first().cloned() {
+            None => {},
+            Some("help") | Some("h") | Some("?") => println!("{}", HELP),
+            Some("step") | Some("s") => self.step(count(1, 1)?),
+            Some("continue") | Some("c") => self.cont(),
+            Some("break") | Some("b") => {
+                let addr = self.address(arg(1)?)?;
+                self.breakpoints.insert(addr);
+                println!("breakpoint at {}", self.describe(addr));
+            },
+            Some("delete") | Some("d") => {
+                let addr = self.address(arg(1)?)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("no breakpoint at {}", self.describe(addr)));
+                }
+            },
+            Some("breakpoints") => {
+                for addr in &self.breakpoints {
+                    println!("{}", self.describe(*addr));
+                }
+            },
+            Some("regs") | Some("r") => self.show_regs(),
+            Some("stack") => self.show_stack(),
+            Some("mem") | Some("x") => self.show_mem(self.address(arg(1)?)?, count(2, 16)?),
+            Some("list") | Some("l") => self.list(count(1, 8)?),
+            Some("labels") => {
+                for (l, addr) in &self.labels {
+                    println!("@{:02x} {}", addr, l);
+                }
+            },
+            Some("set") => {
+                let reg = match Reg::from_str(arg(1)?) {
+                    Ok(Reg::UNKNOWN) | Err(_) => return Err(format!("unknown register '{}'", tokens[1])),
+                    Ok(reg) => reg,
+                };
+                self.machine.set_reg(reg, value(arg(2)?)?);
+            },
+            Some("poke") => {
+                let addr = self.address(arg(1)?)?;
+                self.machine.set_mem(addr, value(arg(2)?)?);
+            },
+            Some("reset") => {
+                self.machine = machine(self.machine.rom(), &self.args)?;
+                self.show_current();
+            },
+            Some("quit") | Some("q") => return Ok(false),
+            Some(other) => return Err(format!("unknown command '{}' (try help)", other)),
+        }
+
+        Ok(true)
+    }
+}
+
+fn main() -> Result<(), std::io::Error> {
+
+    let args : Vec<String> = std::env::args().collect();
+    let path = match args.iter().skip(1).find(|a| !a.starts_with("--")) {
+        Some(path) => path.clone(),
+        None => {
+            println!("usage: debugger <program.asm | image.hex> [--input=file] [--peripheral=kind@address,...] [--peripherals=file.toml]");
+            return Err(std::io::Error::from(io::ErrorKind::InvalidInput));
+        }
+    };
+
+    let source = std::fs::read_to_string(&path)?;
+
+    // accept either assembler source (with labels) or a raw ROM image
+    let (rom, labels) = if source.starts_with("v2.0 raw") {
+        let rom = parse_image(&source).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        (rom, BTreeMap::new())
+    } else {
+        match assemble_source(&path, &source, false) {
+            Ok((instructions, labels)) => (encode_rom(&instructions), labels),
+            Err(errors) => {
+                for e in &errors {
+                    eprintln!("{}", e);
+                }
+                return Err(std::io::Error::from(io::ErrorKind::InvalidData));
+            }
+        }
+    };
+
+    let machine = machine(&rom, &args).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut debugger = Debugger {
+        machine,
+        labels,
+        breakpoints: BTreeSet::new(),
+        args,
+    };
+
+    println!("loaded {} bytes from {}; type help for commands", rom.len(), path);
+    debugger.show_current();
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+
+        match debugger.command(&line) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    Ok(())
+}
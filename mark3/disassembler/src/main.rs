@@ -0,0 +1,62 @@
+use std::io::{self, Read};
+use std::collections::{BTreeMap, BTreeSet};
+
+use common::*;
+
+fn main() -> Result<(), std::io::Error> {
+
+    let rom = {
+        let mut s = String::new();
+        let stdin = io::stdin();
+        stdin.lock().read_to_string(&mut s)?;
+        parse_image(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+    };
+
+    let instructions = disassemble(&rom);
+
+    // only jump targets that land on an instruction boundary get a label
+    let labels : BTreeMap<u8, String> = {
+        let starts : BTreeSet<u8> = instructions.iter().map(|(pc, _)| *pc).collect();
+        let mut labels = BTreeMap::new();
+        for (_, i) in &instructions {
+            match i {
+                Instruction::Jmp(Target::Absolute(t))
+                | Instruction::Jz(Target::Absolute(t))
+                | Instruction::Jnz(Target::Absolute(t)) if starts.contains(t) => {
+                    labels.insert(*t, format!(":l_{:02x}", t));
+                },
+                _ => {}
+            }
+        }
+        labels
+    };
+
+    println!("# disassembled {} bytes", rom.len());
+
+    for (pc, i) in &instructions {
+        if let Some(l) = labels.get(pc) {
+            println!("{}", l);
+        }
+
+        let bytes = match i.encode() {
+            (first, Some(second)) => format!("{:02x} {:02x}", first, second),
+            (first, None) => format!("{:02x}", first),
+        };
+
+        let label = |t: &Target| match t {
+            Target::Absolute(c) if labels.contains_key(c) => Target::Label(labels[c].clone()),
+            _ => t.clone(),
+        };
+
+        let i = match i {
+            Instruction::Jmp(t) => Instruction::Jmp(label(t)),
+            Instruction::Jz(t) => Instruction::Jz(label(t)),
+            Instruction::Jnz(t) => Instruction::Jnz(label(t)),
+            _ => i.clone(),
+        };
+
+        println!("{:<24}# @{:02x}: {}", i.to_string(), pc, bytes);
+    }
+
+    Ok(())
+}